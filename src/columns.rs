@@ -0,0 +1,184 @@
+use crate::error::Error;
+use crate::Regex;
+
+/// Selects the columns to operate on.
+///
+/// A plain string converts to [`ColumnSelector::Spec`].
+#[derive(Clone, Debug)]
+pub enum ColumnSelector {
+    /// A comma separated list of column names, zero based indexes and
+    /// ranges like `2-5` or `3-`. Negative indexes count from the end and
+    /// `*` selects all columns. Names can only be used when the reader has
+    /// a header row.
+    Spec(String),
+    /// A regular expression matched against the column names. Every
+    /// matching column is selected, and it is an error if none match.
+    Regex(String),
+}
+
+impl From<&str> for ColumnSelector {
+    fn from(spec: &str) -> Self {
+        ColumnSelector::Spec(spec.to_string())
+    }
+}
+
+impl From<String> for ColumnSelector {
+    fn from(spec: String) -> Self {
+        ColumnSelector::Spec(spec)
+    }
+}
+
+/// A [`ColumnSelector`] with the regular expression compiled.
+#[derive(Clone, Debug)]
+pub(crate) enum Selector {
+    Spec(String),
+    Regex(Regex),
+}
+
+impl Selector {
+    pub(crate) fn new(selector: &ColumnSelector, bytes: bool) -> Result<Selector, regex::Error> {
+        Ok(match selector {
+            ColumnSelector::Spec(spec) => Selector::Spec(spec.clone()),
+            ColumnSelector::Regex(re) => Selector::Regex(Regex::new(re, bytes)?),
+        })
+    }
+
+    /// Resolve the selected columns against `headers`.
+    ///
+    /// If the reader has no header row, `headers` is the first record and is
+    /// only used to find out the number of columns.
+    pub(crate) fn resolve(
+        &self,
+        headers: &csv::ByteRecord,
+        has_headers: bool,
+    ) -> Result<Columns, Error> {
+        match self {
+            Selector::Spec(spec) => Columns::resolve(spec, headers, has_headers),
+            Selector::Regex(re) => {
+                if !has_headers {
+                    return Err(Error::NoHeaders(re.as_str().to_string()));
+                }
+                let indexes: Vec<usize> = headers
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, name)| re.is_match(name))
+                    .map(|(index, _)| index)
+                    .collect();
+                if indexes.is_empty() {
+                    return Err(Error::NoMatchingColumns(re.as_str().to_string()));
+                }
+                Ok(Columns { indexes })
+            }
+        }
+    }
+}
+
+/// A set of column indexes resolved from a column specification.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Columns {
+    indexes: Vec<usize>,
+}
+
+impl Columns {
+    fn resolve(spec: &str, headers: &csv::ByteRecord, has_headers: bool) -> Result<Columns, Error> {
+        let resolver = Resolver {
+            headers,
+            has_headers,
+        };
+
+        let mut indexes = Vec::new();
+
+        // A name containing commas is only usable as a whole.
+        match resolver.single(spec)? {
+            Some(index) => indexes.push(index),
+            None => {
+                for item in spec.split(',') {
+                    resolver.item(item, &mut indexes)?;
+                }
+            }
+        }
+
+        indexes.sort_unstable();
+        indexes.dedup();
+
+        Ok(Columns { indexes })
+    }
+
+    pub(crate) fn contains(&self, index: usize) -> bool {
+        self.indexes.binary_search(&index).is_ok()
+    }
+
+    pub(crate) fn indexes(&self) -> &[usize] {
+        &self.indexes
+    }
+}
+
+struct Resolver<'h> {
+    headers: &'h csv::ByteRecord,
+    has_headers: bool,
+}
+
+impl<'h> Resolver<'h> {
+    fn item(&self, item: &str, indexes: &mut Vec<usize>) -> Result<(), Error> {
+        if item == "*" {
+            indexes.extend(0..self.headers.len());
+            return Ok(());
+        }
+
+        if let Some(index) = self.single(item)? {
+            indexes.push(index);
+            return Ok(());
+        }
+
+        // The first character is skipped so that a negative start of a
+        // range is not taken as the separator.
+        if let Some(pos) = item.char_indices().skip(1).find(|&(_, c)| c == '-') {
+            let (start, end) = (&item[..pos.0], &item[pos.0 + 1..]);
+            if let Some(start) = self.single(start)? {
+                let end = if end.is_empty() {
+                    self.headers.len().saturating_sub(1)
+                } else {
+                    match self.single(end)? {
+                        Some(end) => end,
+                        None => return self.not_found(end),
+                    }
+                };
+                indexes.extend(start.min(end)..=start.max(end));
+                return Ok(());
+            }
+        }
+
+        self.not_found(item)
+    }
+
+    // Resolve a single index or name. Returns `None` if `item` is neither.
+    fn single(&self, item: &str) -> Result<Option<usize>, Error> {
+        if let Ok(index) = item.parse::<isize>() {
+            if index >= 0 {
+                return Ok(Some(index as usize));
+            }
+            let from_end = index.unsigned_abs();
+            if from_end > self.headers.len() {
+                return Err(Error::ColumnNotFound);
+            }
+            return Ok(Some(self.headers.len() - from_end));
+        }
+
+        if self.has_headers {
+            Ok(self.headers.iter().position(|x| x == item.as_bytes()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // Without headers only indexes are valid, so report the parse error to
+    // point that out.
+    fn not_found(&self, item: &str) -> Result<(), Error> {
+        if self.has_headers {
+            Err(Error::ColumnNotFound)
+        } else {
+            item.parse::<isize>()?;
+            Err(Error::ColumnNotFound)
+        }
+    }
+}
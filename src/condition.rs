@@ -0,0 +1,69 @@
+use crate::columns::{ColumnSelector, Columns, Selector};
+use crate::error::Error;
+use crate::Regex;
+
+/// A condition on a record that decides whether the rules are applied to
+/// it.
+///
+/// A condition is met if its regex matches in any of its columns, or with
+/// [`Condition::negate`], if it matches in none of them.
+#[derive(Clone, Debug)]
+pub struct Condition {
+    column: ColumnSelector,
+    regex: String,
+    negate: bool,
+}
+
+impl Condition {
+    /// Create a condition that is met when `regex` matches in `column`.
+    pub fn new<C>(column: C, regex: &str) -> Condition
+    where
+        C: Into<ColumnSelector>,
+    {
+        Condition {
+            column: column.into(),
+            regex: regex.to_string(),
+            negate: false,
+        }
+    }
+
+    /// Require that the regex does not match instead.
+    pub fn negate(mut self, yes: bool) -> Condition {
+        self.negate = yes;
+        self
+    }
+
+    pub(crate) fn compile(&self, bytes: bool) -> Result<CompiledCondition, regex::Error> {
+        Ok(CompiledCondition {
+            column: Selector::new(&self.column, bytes)?,
+            regex: Regex::new(&self.regex, bytes)?,
+            negate: self.negate,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CompiledCondition {
+    column: Selector,
+    regex: Regex,
+    negate: bool,
+}
+
+impl CompiledCondition {
+    pub(crate) fn columns(
+        &self,
+        headers: &csv::ByteRecord,
+        has_headers: bool,
+    ) -> Result<Columns, Error> {
+        self.column.resolve(headers, has_headers)
+    }
+
+    pub(crate) fn is_met(&self, columns: &Columns, record: &csv::ByteRecord) -> bool {
+        let matched = columns
+            .indexes()
+            .iter()
+            .filter_map(|&index| record.get(index))
+            .any(|field| self.regex.is_match(field));
+        matched != self.negate
+    }
+}
@@ -0,0 +1,257 @@
+use std::io::{self, Read};
+
+/// The format of a CSV file.
+///
+/// The defaults are the same as the defaults of `csv::ReaderBuilder` and
+/// `csv::WriterBuilder`.
+#[derive(Clone, Copy, Debug)]
+pub struct Dialect {
+    /// The field delimiter.
+    pub delimiter: u8,
+    /// The quote character.
+    pub quote: u8,
+    /// Whether quotes are special at all. Only used for reading.
+    pub quoting: bool,
+    /// The escape character for quotes, if quotes are not escaped by
+    /// doubling them.
+    pub escape: Option<u8>,
+    /// Whether two quotes in a quoted field mean one quote.
+    pub double_quote: bool,
+    /// Lines starting with this character are ignored. Only used for
+    /// reading.
+    pub comment: Option<u8>,
+    /// Which whitespace to trim. Only used for reading.
+    pub trim: csv::Trim,
+    /// When to quote fields. Only used for writing.
+    pub quote_style: csv::QuoteStyle,
+    /// The record terminator. Only used for writing.
+    pub terminator: csv::Terminator,
+}
+
+impl Default for Dialect {
+    fn default() -> Dialect {
+        Dialect {
+            delimiter: b',',
+            quote: b'"',
+            quoting: true,
+            escape: None,
+            double_quote: true,
+            comment: None,
+            trim: csv::Trim::None,
+            quote_style: csv::QuoteStyle::Necessary,
+            terminator: csv::Terminator::Any(b'\n'),
+        }
+    }
+}
+
+impl Dialect {
+    /// Look up a dialect by name.
+    ///
+    /// The known dialects are `excel` and `rfc4180`, which are comma
+    /// separated with CRLF line terminators, and `tsv`, which is tab
+    /// separated without any quoting.
+    pub fn preset(name: &str) -> Option<Dialect> {
+        match name {
+            "excel" | "rfc4180" => Some(Dialect {
+                terminator: csv::Terminator::CRLF,
+                ..Dialect::default()
+            }),
+            "tsv" => Some(Dialect {
+                delimiter: b'\t',
+                quoting: false,
+                quote_style: csv::QuoteStyle::Never,
+                ..Dialect::default()
+            }),
+            _ => None,
+        }
+    }
+
+    /// Create a reader builder configured for this dialect.
+    pub fn reader_builder(&self) -> csv::ReaderBuilder {
+        let mut builder = csv::ReaderBuilder::new();
+        builder
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .quoting(self.quoting)
+            .escape(self.escape)
+            .double_quote(self.double_quote)
+            .comment(self.comment)
+            .trim(self.trim);
+        builder
+    }
+
+    /// Create a writer builder configured for this dialect.
+    pub fn writer_builder(&self) -> csv::WriterBuilder {
+        let mut builder = csv::WriterBuilder::new();
+        builder
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .double_quote(self.double_quote)
+            .quote_style(self.quote_style)
+            .terminator(self.terminator);
+        if let Some(escape) = self.escape {
+            builder.escape(escape);
+        }
+        builder
+    }
+}
+
+/// What [`sniff`] found out about a sample of CSV data.
+#[derive(Clone, Copy, Debug)]
+pub struct Sniffed {
+    /// The field delimiter.
+    pub delimiter: u8,
+    /// The quote character.
+    pub quote: u8,
+    /// Whether the first record looks like a header row.
+    pub has_headers: bool,
+}
+
+const DELIMITERS: &[u8] = b",;\t|:";
+const QUOTES: &[u8] = b"\"'";
+
+/// Guess the delimiter, quote character and presence of a header row from
+/// the beginning of some CSV data.
+///
+/// The delimiter is the candidate that splits most records into the same
+/// number of fields. The header row is detected by comparing the first
+/// record to the rest, column by column: a column of numbers with a non
+/// numeric first value, or a column of values of the same length with a
+/// first value of a different length, suggests a header. Without evidence
+/// either way, a header row is assumed.
+///
+/// If `sample` does not end with a newline, its last record is assumed to
+/// be cut off and is ignored.
+pub fn sniff(sample: &[u8]) -> Sniffed {
+    let sample = match sample.iter().rposition(|&b| b == b'\n') {
+        Some(end) if end + 1 < sample.len() => &sample[..=end],
+        _ => sample,
+    };
+
+    let mut best = (0, 0);
+    let mut sniffed = Sniffed {
+        delimiter: b',',
+        quote: b'"',
+        has_headers: true,
+    };
+    let mut best_records = Vec::new();
+
+    for &delimiter in DELIMITERS {
+        let quote = guess_quote(sample, delimiter);
+        let records = parse(sample, delimiter, quote);
+        let score = consistency(&records);
+        if score > best {
+            best = score;
+            sniffed.delimiter = delimiter;
+            sniffed.quote = quote;
+            best_records = records;
+        }
+    }
+
+    sniffed.has_headers = looks_like_headers(&best_records);
+    sniffed
+}
+
+/// A reader returned by [`sniff_reader`].
+pub type SniffedReader<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// Read up to `limit` bytes from `reader` and [`sniff`] them.
+///
+/// Returns a reader that yields the sniffed bytes followed by the rest of
+/// the input.
+pub fn sniff_reader<R: io::Read>(
+    mut reader: R,
+    limit: usize,
+) -> io::Result<(Sniffed, SniffedReader<R>)> {
+    let mut sample = Vec::new();
+    (&mut reader).take(limit as u64).read_to_end(&mut sample)?;
+    let sniffed = sniff(&sample);
+    Ok((sniffed, io::Cursor::new(sample).chain(reader)))
+}
+
+// Quotes are only special at the start of a field, so count how many fields
+// start with each candidate.
+fn guess_quote(sample: &[u8], delimiter: u8) -> u8 {
+    let starts = |quote: u8| {
+        sample
+            .iter()
+            .enumerate()
+            .filter(|&(i, &b)| {
+                b == quote && (i == 0 || sample[i - 1] == delimiter || sample[i - 1] == b'\n')
+            })
+            .count()
+    };
+
+    let mut best = QUOTES[0];
+    for &quote in &QUOTES[1..] {
+        if starts(quote) > starts(best) {
+            best = quote;
+        }
+    }
+    best
+}
+
+fn parse(sample: &[u8], delimiter: u8, quote: u8) -> Vec<csv::ByteRecord> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .quote(quote)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(sample);
+
+    reader.byte_records().filter_map(Result::ok).collect()
+}
+
+// Returns how many records have the most common number of fields, and that
+// number. A single field does not count, as any delimiter could produce it.
+fn consistency(records: &[csv::ByteRecord]) -> (usize, usize) {
+    let mut counts: Vec<(usize, usize)> = Vec::new();
+
+    for record in records {
+        match counts
+            .iter_mut()
+            .find(|(fields, _)| *fields == record.len())
+        {
+            Some((_, n)) => *n += 1,
+            None => counts.push((record.len(), 1)),
+        }
+    }
+
+    counts
+        .into_iter()
+        .filter(|&(fields, _)| fields > 1)
+        .map(|(fields, n)| (n, fields))
+        .max()
+        .unwrap_or((0, 0))
+}
+
+fn looks_like_headers(records: &[csv::ByteRecord]) -> bool {
+    let (first, rest) = match records.split_first() {
+        Some((first, rest)) if !rest.is_empty() => (first, rest),
+        _ => return true,
+    };
+
+    let mut votes = 0;
+
+    for (index, name) in first.iter().enumerate() {
+        let values: Vec<&[u8]> = rest.iter().filter_map(|r| r.get(index)).collect();
+        if values.is_empty() {
+            continue;
+        }
+
+        if values.iter().all(|v| is_number(v)) {
+            votes += if is_number(name) { -1 } else { 1 };
+        } else if values.iter().all(|v| v.len() == values[0].len()) {
+            votes += if name.len() == values[0].len() { -1 } else { 1 };
+        }
+    }
+
+    votes >= 0
+}
+
+fn is_number(field: &[u8]) -> bool {
+    std::str::from_utf8(field)
+        .ok()
+        .and_then(|s| s.trim().parse::<f64>().ok())
+        .is_some()
+}
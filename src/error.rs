@@ -0,0 +1,238 @@
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+
+use crate::rule::Rule;
+
+/// The error type for all operations in this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// The requested column could not be found from the headers.
+    ColumnNotFound,
+    /// No column names matched the column selection regex.
+    NoMatchingColumns(String),
+    /// Columns were selected with a regex, but there is no header row to
+    /// match it against.
+    NoHeaders(String),
+    /// The headers of an input do not match the headers of the first one.
+    HeaderMismatch,
+    /// An error from the CSV reader or writer.
+    Csv(csv::Error),
+    /// An I/O error.
+    Io(io::Error),
+    /// A regular expression could not be compiled.
+    Regex(regex::Error),
+    /// One or more rules could not be compiled.
+    Rules(Vec<RuleError>),
+    /// A rule on the given line of a rule file has invalid settings.
+    InvalidRule(usize, &'static str),
+    /// A rule file could not be parsed.
+    Toml(toml::de::Error),
+    /// An error while processing the given file.
+    File(PathBuf, Box<Error>),
+    /// A column index could not be parsed.
+    ParseInt(std::num::ParseIntError),
+    /// A regex did not match the record on the given line while
+    /// extracting.
+    NoMatch(u64),
+    /// The mode or reference needs exactly one column to be selected.
+    SingleColumn,
+    /// The mode needs exactly one rule.
+    SingleRule,
+    /// One or more replacements reference capture groups that their
+    /// regexes do not have.
+    Templates(Vec<TemplateError>),
+}
+
+impl Error {
+    /// Returns true if this is an I/O error caused by a broken pipe.
+    pub fn is_broken_pipe(&self) -> bool {
+        match self {
+            Error::Io(e) => e.kind() == io::ErrorKind::BrokenPipe,
+            Error::File(_, e) => e.is_broken_pipe(),
+            _ => false,
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::ColumnNotFound => write!(f, "column not found"),
+            Error::NoMatchingColumns(re) => write!(f, "no column names match regex {}", re),
+            Error::NoHeaders(re) => {
+                write!(f, "column regex {} cannot be used without headers", re)
+            }
+            Error::HeaderMismatch => write!(f, "headers do not match the first input"),
+            Error::Csv(e) => e.fmt(f),
+            Error::Io(e) => e.fmt(f),
+            Error::Regex(e) => e.fmt(f),
+            Error::Rules(errors) => {
+                for (index, e) in errors.iter().enumerate() {
+                    if index > 0 {
+                        writeln!(f)?;
+                    }
+                    e.fmt(f)?;
+                }
+                Ok(())
+            }
+            Error::InvalidRule(line, message) => write!(f, "rule on line {}: {}", line, message),
+            Error::Toml(e) => e.fmt(f),
+            Error::File(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::ParseInt(e) => e.fmt(f),
+            Error::NoMatch(line) => write!(f, "no match on line {}", line),
+            Error::SingleColumn => write!(f, "exactly one column must be selected"),
+            Error::SingleRule => write!(f, "exactly one rule must be given"),
+            Error::Templates(errors) => {
+                for (index, e) in errors.iter().enumerate() {
+                    if index > 0 {
+                        writeln!(f)?;
+                    }
+                    e.fmt(f)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(error: csv::Error) -> Self {
+        if error.is_io_error() {
+            let error = error.into_kind();
+            match error {
+                csv::ErrorKind::Io(e) => Error::Io(e),
+                _ => unreachable!(),
+            }
+        } else {
+            Error::Csv(error)
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<regex::Error> for Error {
+    fn from(error: regex::Error) -> Self {
+        Error::Regex(error)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(error: toml::de::Error) -> Self {
+        Error::Toml(error)
+    }
+}
+
+impl From<std::num::ParseIntError> for Error {
+    fn from(error: std::num::ParseIntError) -> Self {
+        Error::ParseInt(error)
+    }
+}
+
+/// A rule that could not be compiled.
+#[derive(Debug)]
+pub struct RuleError {
+    rule: String,
+    line: Option<usize>,
+    error: regex::Error,
+}
+
+// Rules are named in errors by their name, or by their number.
+fn label(rule: &Rule, index: usize) -> String {
+    match rule.name {
+        Some(ref name) => format!("{:?}", name),
+        None => format!("#{}", index + 1),
+    }
+}
+
+impl RuleError {
+    pub(crate) fn new(rule: &Rule, index: usize, error: regex::Error) -> RuleError {
+        RuleError {
+            rule: label(rule, index),
+            line: rule.line,
+            error,
+        }
+    }
+
+    /// The line of the rule in the rule file, if known.
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+
+    /// The underlying regex error.
+    pub fn regex_error(&self) -> &regex::Error {
+        &self.error
+    }
+}
+
+impl std::fmt::Display for RuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "rule {}", self.rule)?;
+        if let Some(line) = self.line {
+            write!(f, " on line {}", line)?;
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+/// An invalid reference in the replacement of a rule.
+#[derive(Debug)]
+pub struct TemplateError {
+    rule: String,
+    line: Option<usize>,
+    replacement: String,
+    span: Range<usize>,
+    message: String,
+}
+
+impl TemplateError {
+    pub(crate) fn new(
+        rule: &Rule,
+        index: usize,
+        span: Range<usize>,
+        message: String,
+    ) -> TemplateError {
+        TemplateError {
+            rule: label(rule, index),
+            line: rule.line,
+            replacement: rule.replacement().to_string(),
+            span,
+            message,
+        }
+    }
+
+    /// The line of the rule in the rule file, if known.
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+
+    /// The byte range of the invalid reference in the replacement.
+    pub fn span(&self) -> Range<usize> {
+        self.span.clone()
+    }
+}
+
+// The replacement is shown with the reference underlined.
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "rule {}", self.rule)?;
+        if let Some(line) = self.line {
+            write!(f, " on line {}", line)?;
+        }
+        writeln!(f, ": {} in replacement", self.message)?;
+        writeln!(f, "    {}", self.replacement)?;
+        write!(
+            f,
+            "    {}{}",
+            " ".repeat(self.replacement[..self.span.start].chars().count()),
+            "^".repeat(self.replacement[self.span.clone()].chars().count())
+        )
+    }
+}
@@ -0,0 +1,894 @@
+//! Replace data in CSV columns with regular expressions.
+//!
+//! This is the library behind the `csvre` command line tool. The same
+//! transformation can be embedded in other programs by building a
+//! [`Replacer`] and running it over any `csv::Reader` and `csv::Writer`
+//! pair:
+//!
+//! ```
+//! let input = "name,phone\nfoo,555 12 34\n";
+//! let mut reader = csv::Reader::from_reader(input.as_bytes());
+//! let mut writer = csv::Writer::from_writer(vec![]);
+//!
+//! let replacer = csvre::ReplacerBuilder::new()
+//!     .rule(csvre::Rule::new("phone", r"\s+", ""))
+//!     .build()?;
+//! replacer.run(&mut reader, &mut writer)?;
+//!
+//! let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+//! assert_eq!(output, "name,phone\nfoo,5551234\n");
+//! # Ok::<(), csvre::Error>(())
+//! ```
+
+use std::borrow::Cow;
+use std::io;
+
+mod columns;
+mod condition;
+mod dialect;
+mod error;
+mod rule;
+mod split;
+mod template;
+
+pub use crate::columns::ColumnSelector;
+pub use crate::condition::Condition;
+pub use crate::dialect::{sniff, sniff_reader, Dialect, Sniffed, SniffedReader};
+pub use crate::error::{Error, RuleError, TemplateError};
+pub use crate::rule::{parse_rules, Rule};
+pub use crate::split::{literal_separator, SplitReader};
+
+use crate::columns::Columns;
+use crate::condition::CompiledCondition;
+use crate::rule::{CompiledRule, Occurrences};
+use crate::template::{Group, Template};
+
+/// What a [`Replacer`] does with the records.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Replace the matches of each rule in its columns.
+    #[default]
+    Replace,
+    /// Only output the records where some rule matches in its columns, or
+    /// with `invert`, the records where none of the rules match. The
+    /// replacements are not used.
+    Filter {
+        /// Output the records that don't match instead.
+        invert: bool,
+    },
+    /// Append the capture groups of each rule as new columns, leaving the
+    /// original columns as they are.
+    ///
+    /// The new columns are named after the capture groups. Unnamed groups
+    /// are called `{column}_{index}`, and if a rule selects several
+    /// columns, the named groups are called `{column}_{name}`. A regex
+    /// without any groups extracts the whole match. The replacements are
+    /// not used.
+    Extract {
+        /// What to do with records where a regex does not match.
+        unmatched: Unmatched,
+    },
+    /// Output a copy of the record for each match of the regex, with the
+    /// column replaced by the match. A record without any matches is
+    /// output once with the column empty.
+    ///
+    /// This needs a single rule that selects a single column. The
+    /// replacement is not used.
+    Explode {
+        /// Use the regex as a separator, and output a copy of the record
+        /// for each piece between the matches instead.
+        pieces: bool,
+    },
+    /// Use the regex as a separator and append the pieces of the column
+    /// as new columns called `{column}_1`, `{column}_2` and so on.
+    ///
+    /// This needs a single rule that selects a single column. The
+    /// replacement is not used.
+    Split {
+        /// The number of new columns. The last one holds the rest of the
+        /// field if there are more pieces. By default this is the number
+        /// of pieces in the first record.
+        count: Option<usize>,
+        /// Add empty fields to records with fewer pieces than new
+        /// columns.
+        pad: bool,
+        /// Keep the original column. Otherwise it is left out.
+        keep: bool,
+    },
+}
+
+/// What [`Mode::Extract`] does when a regex does not match.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Unmatched {
+    /// Leave the extracted columns empty.
+    #[default]
+    Empty,
+    /// Leave the record out of the output.
+    Skip,
+    /// Fail with [`Error::NoMatch`].
+    Error,
+}
+
+/// Builds a [`Replacer`].
+#[derive(Clone, Debug, Default)]
+pub struct ReplacerBuilder {
+    rules: Vec<Rule>,
+    conditions: Vec<Condition>,
+    any: bool,
+    mode: Mode,
+    bytes: bool,
+}
+
+impl ReplacerBuilder {
+    /// Create a new builder without any rules.
+    pub fn new() -> ReplacerBuilder {
+        ReplacerBuilder::default()
+    }
+
+    /// Add a rule.
+    ///
+    /// The rules are applied to each record in the order they were added,
+    /// so a rule sees the result of the rules before it.
+    pub fn rule(&mut self, rule: Rule) -> &mut ReplacerBuilder {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Add a condition that a record must meet for the rules to be
+    /// applied to it. Records that don't meet the conditions are written
+    /// unchanged, and new columns added with [`Rule::output_column`] get
+    /// the original value.
+    ///
+    /// By default all of the conditions must be met. Only used in
+    /// [`Mode::Replace`].
+    pub fn condition(&mut self, condition: Condition) -> &mut ReplacerBuilder {
+        self.conditions.push(condition);
+        self
+    }
+
+    /// Apply the rules if any of the conditions is met, instead of all of
+    /// them.
+    pub fn any(&mut self, yes: bool) -> &mut ReplacerBuilder {
+        self.any = yes;
+        self
+    }
+
+    /// Set what is done with the records.
+    ///
+    /// The default is [`Mode::Replace`].
+    pub fn mode(&mut self, mode: Mode) -> &mut ReplacerBuilder {
+        self.mode = mode;
+        self
+    }
+
+    /// Work on raw bytes instead of assuming utf-8 input.
+    ///
+    /// This is disabled by default.
+    pub fn bytes(&mut self, yes: bool) -> &mut ReplacerBuilder {
+        self.bytes = yes;
+        self
+    }
+
+    /// Compile the regular expressions and build the replacer.
+    ///
+    /// All rules are compiled before returning, so that every invalid
+    /// regular expression is reported in [`Error::Rules`]. If they are all
+    /// valid, every reference to a capture group that a regex does not have
+    /// is reported in [`Error::Templates`].
+    pub fn build(&self) -> Result<Replacer, Error> {
+        let mut rules = Vec::with_capacity(self.rules.len());
+        let mut errors = Vec::new();
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            match rule.compile(self.bytes) {
+                Ok(rule) => rules.push(rule),
+                Err(error) => errors.push(RuleError::new(rule, index, error)),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(Error::Rules(errors));
+        }
+
+        let errors: Vec<TemplateError> = self
+            .rules
+            .iter()
+            .zip(&rules)
+            .enumerate()
+            .flat_map(|(index, (rule, compiled))| {
+                compiled
+                    .unknown_groups()
+                    .into_iter()
+                    .map(move |(span, message)| TemplateError::new(rule, index, span, message))
+            })
+            .collect();
+
+        if !errors.is_empty() {
+            return Err(Error::Templates(errors));
+        }
+
+        let conditions = self
+            .conditions
+            .iter()
+            .map(|condition| condition.compile(self.bytes))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Replacer {
+            rules,
+            conditions,
+            any: self.any,
+            mode: self.mode,
+            bytes: self.bytes,
+        })
+    }
+}
+
+/// Replaces data in CSV columns with regular expressions.
+///
+/// A replacer is created with a [`ReplacerBuilder`].
+#[derive(Clone, Debug)]
+pub struct Replacer {
+    rules: Vec<CompiledRule>,
+    conditions: Vec<CompiledCondition>,
+    any: bool,
+    mode: Mode,
+    bytes: bool,
+}
+
+impl Replacer {
+    /// Read all records from `reader`, apply the rules and write the
+    /// result to `writer`.
+    ///
+    /// If the reader has headers, they are written to the writer as is. The
+    /// writer is not flushed.
+    pub fn run<R, W>(
+        &self,
+        reader: &mut csv::Reader<R>,
+        writer: &mut csv::Writer<W>,
+    ) -> Result<(), Error>
+    where
+        R: io::Read,
+        W: io::Write,
+    {
+        self.concat(writer).run(reader)
+    }
+
+    /// Start writing the results of several readers to `writer`.
+    ///
+    /// See [`Concat`] for details.
+    pub fn concat<'a, W: io::Write>(&'a self, writer: &'a mut csv::Writer<W>) -> Concat<'a, W> {
+        Concat {
+            replacer: self,
+            writer,
+            headers: None,
+            source_column: None,
+            split_count: None,
+        }
+    }
+
+    // The headers are always handled as bytes, but in utf-8 mode they are
+    // validated first.
+    fn headers<'r, R: io::Read>(
+        &self,
+        reader: &'r mut csv::Reader<R>,
+    ) -> Result<&'r csv::ByteRecord, Error> {
+        if !self.bytes {
+            reader.headers()?;
+        }
+        Ok(reader.byte_headers()?)
+    }
+
+    fn read_record<'a, R: io::Read>(
+        &self,
+        reader: &mut csv::Reader<R>,
+        string_record: &'a mut csv::StringRecord,
+        byte_record: &'a mut csv::ByteRecord,
+    ) -> Result<Option<&'a csv::ByteRecord>, Error> {
+        if self.bytes {
+            if reader.read_byte_record(byte_record)? {
+                return Ok(Some(byte_record));
+            }
+        } else if reader.read_record(string_record)? {
+            return Ok(Some(string_record.as_byte_record()));
+        }
+        Ok(None)
+    }
+}
+
+/// Applies a replacer to several readers, writing one output.
+///
+/// The headers are only written once. The headers of the rest of the
+/// readers must have the same column names as the first one. If the columns
+/// are in a different order, the records are reordered to match the first
+/// reader. Empty readers are skipped.
+///
+/// A `Concat` is created with [`Replacer::concat`].
+#[derive(Debug)]
+pub struct Concat<'a, W: io::Write> {
+    replacer: &'a Replacer,
+    writer: &'a mut csv::Writer<W>,
+    headers: Option<csv::ByteRecord>,
+    source_column: Option<String>,
+    split_count: Option<usize>,
+}
+
+impl<'a, W: io::Write> Concat<'a, W> {
+    /// Add a column called `name` to the output, containing the name that
+    /// was given to [`Concat::run_named`] for each record.
+    pub fn source_column(&mut self, name: &str) -> &mut Concat<'a, W> {
+        self.source_column = Some(name.to_string());
+        self
+    }
+
+    /// Read all records from `reader`, apply the rules and write the
+    /// result.
+    pub fn run<R: io::Read>(&mut self, reader: &mut csv::Reader<R>) -> Result<(), Error> {
+        self.run_named(reader, "")
+    }
+
+    /// Like [`Concat::run`], but `name` is written to the source column
+    /// if one was added with [`Concat::source_column`].
+    pub fn run_named<R: io::Read>(
+        &mut self,
+        reader: &mut csv::Reader<R>,
+        name: &str,
+    ) -> Result<(), Error> {
+        let replacer = self.replacer;
+        let has_headers = reader.has_headers();
+        let headers = replacer.headers(reader)?;
+
+        // An empty input, like an export without any rows, has no columns
+        // to select or to compare with the first reader.
+        if headers.is_empty() {
+            return Ok(());
+        }
+
+        let order = match self.headers {
+            Some(ref first) if has_headers => align(first, headers)?,
+            _ => None,
+        };
+
+        // Without headers the columns are resolved separately for each
+        // reader, as the first record only tells the number of columns.
+        let reference = match self.headers {
+            Some(ref first) if has_headers => first,
+            _ => headers,
+        };
+
+        let columns = replacer
+            .rules
+            .iter()
+            .map(|rule| rule.columns(reference, has_headers))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Only replacements use the columns referenced with ${col:...}.
+        let references = match replacer.mode {
+            Mode::Replace => replacer
+                .rules
+                .iter()
+                .map(|rule| rule.references(reference, has_headers))
+                .collect::<Result<Vec<_>, _>>()?,
+            _ => Vec::new(),
+        };
+
+        let conditions = replacer
+            .conditions
+            .iter()
+            .map(|condition| condition.columns(reference, has_headers))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let single = match replacer.mode {
+            Mode::Explode { .. } | Mode::Split { .. } => single_column(&columns)?,
+            _ => 0,
+        };
+
+        // The source column and placement of each new column written by a
+        // rule. They are appended while the rules are applied, and moved in
+        // place when the record is written.
+        let mut derived = Vec::new();
+        if replacer.mode == Mode::Replace {
+            for (rule, columns) in replacer.rules.iter().zip(&columns) {
+                if rule.output.is_some() {
+                    let index = single_column(std::slice::from_ref(columns))?;
+                    derived.push((index, rule.after));
+                }
+            }
+        }
+
+        // The header row is written along with the first record, as the
+        // number of columns added by a split may depend on it.
+        let mut pending = None;
+        if has_headers && self.headers.is_none() {
+            self.headers = Some(headers.clone());
+            pending = Some(headers.clone());
+        }
+
+        let mut string_record = csv::StringRecord::new();
+        let mut byte_record = csv::ByteRecord::new();
+        let mut record = csv::ByteRecord::new();
+        let mut scratch = csv::ByteRecord::new();
+
+        while let Some(record_in) =
+            replacer.read_record(reader, &mut string_record, &mut byte_record)?
+        {
+            record.clear();
+
+            match order {
+                Some(ref order) => {
+                    for &index in order {
+                        record.push_field(record_in.get(index).unwrap_or(b""));
+                    }
+                    record.extend(record_in.iter().skip(order.len()));
+                }
+                None => record.extend(record_in),
+            }
+
+            if let Mode::Split { count, .. } = replacer.mode {
+                if self.split_count.is_none() {
+                    let field = record.get(single).unwrap_or(b"");
+                    let pieces = replacer.rules[0].pieces(field, true).len();
+                    self.split_count = Some(count.unwrap_or(pieces));
+                }
+            }
+
+            if let Some(headers) = pending.take() {
+                self.write_headers(&headers, &columns, single, &derived)?;
+            }
+
+            match replacer.mode {
+                Mode::Replace => {
+                    let mut met = replacer
+                        .conditions
+                        .iter()
+                        .zip(&conditions)
+                        .map(|(condition, columns)| condition.is_met(columns, &record));
+                    let apply = if replacer.any {
+                        conditions.is_empty() || met.any(|met| met)
+                    } else {
+                        met.all(|met| met)
+                    };
+                    let rules = replacer.rules.iter().zip(&columns).zip(&references);
+                    for ((rule, columns), references) in rules {
+                        if apply {
+                            rule.apply(columns, references, &record, &mut scratch);
+                        } else {
+                            rule.skip(columns, &record, &mut scratch);
+                        }
+                        std::mem::swap(&mut record, &mut scratch);
+                    }
+                    if derived.iter().any(|&(_, after)| after) {
+                        arrange(&record, &derived, &mut scratch);
+                        std::mem::swap(&mut record, &mut scratch);
+                    }
+                }
+                Mode::Filter { invert } => {
+                    let matched = replacer
+                        .rules
+                        .iter()
+                        .zip(&columns)
+                        .any(|(rule, columns)| rule.is_match(columns, &record));
+                    if matched == invert {
+                        continue;
+                    }
+                }
+                Mode::Extract { unmatched } => {
+                    scratch.clone_from(&record);
+                    let mut matched = true;
+                    for (rule, columns) in replacer.rules.iter().zip(&columns) {
+                        matched &= rule.extract(columns, &scratch, &mut record);
+                    }
+                    if !matched {
+                        match unmatched {
+                            Unmatched::Empty => {}
+                            Unmatched::Skip => continue,
+                            Unmatched::Error => {
+                                let line = record_in.position().map_or(0, |p| p.line());
+                                return Err(Error::NoMatch(line));
+                            }
+                        }
+                    }
+                }
+                Mode::Explode { pieces } => {
+                    std::mem::swap(&mut record, &mut scratch);
+                    let field = scratch.get(single).unwrap_or(b"");
+                    let mut values = replacer.rules[0].pieces(field, pieces);
+                    if values.is_empty() {
+                        values.push(b"");
+                    }
+                    for value in values {
+                        record.clear();
+                        for (index, field) in scratch.iter().enumerate() {
+                            record.push_field(if index == single { value } else { field });
+                        }
+                        if self.source_column.is_some() {
+                            record.push_field(name.as_bytes());
+                        }
+                        self.writer.write_byte_record(&record)?;
+                    }
+                    continue;
+                }
+                Mode::Split { pad, keep, .. } => {
+                    let count = self.split_count.unwrap_or(1);
+                    std::mem::swap(&mut record, &mut scratch);
+                    record.clear();
+                    for (index, field) in scratch.iter().enumerate() {
+                        if keep || index != single {
+                            record.push_field(field);
+                        }
+                    }
+                    let field = scratch.get(single).unwrap_or(b"");
+                    let values = replacer.rules[0].splitn(field, count);
+                    let len = values.len();
+                    record.extend(values);
+                    if pad {
+                        for _ in len..count {
+                            record.push_field(b"");
+                        }
+                    }
+                }
+            }
+
+            if self.source_column.is_some() {
+                record.push_field(name.as_bytes());
+            }
+
+            self.writer.write_byte_record(&record)?;
+        }
+
+        if let Some(headers) = pending {
+            if let Mode::Split { count, .. } = replacer.mode {
+                self.split_count.get_or_insert(count.unwrap_or(1));
+            }
+            self.write_headers(&headers, &columns, single, &derived)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_headers(
+        &mut self,
+        headers: &csv::ByteRecord,
+        columns: &[Columns],
+        single: usize,
+        derived: &[(usize, bool)],
+    ) -> Result<(), Error> {
+        let mut output = csv::ByteRecord::new();
+
+        match self.replacer.mode {
+            Mode::Replace if !derived.is_empty() => {
+                let mut headers = headers.clone();
+                for rule in &self.replacer.rules {
+                    if let Some(ref name) = rule.output {
+                        headers.push_field(name.as_bytes());
+                    }
+                }
+                arrange(&headers, derived, &mut output);
+            }
+            Mode::Extract { .. } => {
+                output.clone_from(headers);
+                for (rule, columns) in self.replacer.rules.iter().zip(columns) {
+                    rule.extract_headers(columns, headers, &mut output);
+                }
+            }
+            Mode::Split { keep, .. } => {
+                for (index, field) in headers.iter().enumerate() {
+                    if keep || index != single {
+                        output.push_field(field);
+                    }
+                }
+                let header = headers.get(single).unwrap_or(b"");
+                for n in 1..=self.split_count.unwrap_or(1) {
+                    let mut name = header.to_vec();
+                    name.extend_from_slice(format!("_{}", n).as_bytes());
+                    output.push_field(&name);
+                }
+            }
+            _ => output.clone_from(headers),
+        }
+
+        if let Some(ref source_column) = self.source_column {
+            output.push_field(source_column.as_bytes());
+        }
+
+        Ok(self.writer.write_byte_record(&output)?)
+    }
+}
+
+// Move the new columns at the end of `record` after their source columns,
+// if they should be inserted there.
+fn arrange(record: &csv::ByteRecord, derived: &[(usize, bool)], out: &mut csv::ByteRecord) {
+    let len = record.len() - derived.len();
+    out.clear();
+
+    for (index, field) in record.iter().take(len).enumerate() {
+        out.push_field(field);
+        for (n, &(source, after)) in derived.iter().enumerate() {
+            if after && source == index {
+                out.push_field(&record[len + n]);
+            }
+        }
+    }
+
+    for (n, &(source, after)) in derived.iter().enumerate() {
+        if !after || source >= len {
+            out.push_field(&record[len + n]);
+        }
+    }
+}
+
+// Explode and split need exactly one rule with one column.
+fn single_column(columns: &[Columns]) -> Result<usize, Error> {
+    match columns {
+        [columns] => match columns.indexes() {
+            [index] => Ok(*index),
+            _ => Err(Error::SingleColumn),
+        },
+        _ => Err(Error::SingleRule),
+    }
+}
+
+// Find out where each of the columns in `first` are in `headers`. Returns
+// `None` if the headers are the same.
+fn align(first: &csv::ByteRecord, headers: &csv::ByteRecord) -> Result<Option<Vec<usize>>, Error> {
+    if first == headers {
+        return Ok(None);
+    }
+
+    if first.len() != headers.len() {
+        return Err(Error::HeaderMismatch);
+    }
+
+    let mut used = vec![false; headers.len()];
+    let mut order = Vec::with_capacity(first.len());
+
+    for name in first {
+        let index = (0..headers.len())
+            .find(|&index| !used[index] && &headers[index] == name)
+            .ok_or(Error::HeaderMismatch)?;
+        used[index] = true;
+        order.push(index);
+    }
+
+    Ok(Some(order))
+}
+
+/// Either a utf-8 or a byte oriented regular expression.
+///
+/// Records are always handled as bytes. In utf-8 mode the records have
+/// already been validated by the CSV reader before they get here.
+#[derive(Clone, Debug)]
+pub(crate) enum Regex {
+    Str(regex::Regex),
+    Bytes(regex::bytes::Regex),
+}
+
+impl Regex {
+    pub(crate) fn new(re: &str, bytes: bool) -> Result<Regex, regex::Error> {
+        Ok(if bytes {
+            Regex::Bytes(regex::bytes::Regex::new(re)?)
+        } else {
+            Regex::Str(regex::Regex::new(re)?)
+        })
+    }
+
+    /// Compile `re` with flags like the ones given inline with `(?flags)`:
+    /// `i`, `m`, `s`, `x`, `U` and `u`, where the ones after a `-` are
+    /// turned off.
+    pub(crate) fn with_flags(re: &str, flags: &str, bytes: bool) -> Result<Regex, regex::Error> {
+        let flags = Flags::parse(flags).map_err(|flag| {
+            regex::Error::Syntax(format!("unrecognized flag {:?} in flags {:?}", flag, flags))
+        })?;
+
+        Ok(if bytes {
+            Regex::Bytes(
+                regex::bytes::RegexBuilder::new(re)
+                    .case_insensitive(flags.case_insensitive)
+                    .multi_line(flags.multi_line)
+                    .dot_matches_new_line(flags.dot_matches_new_line)
+                    .ignore_whitespace(flags.ignore_whitespace)
+                    .swap_greed(flags.swap_greed)
+                    .unicode(flags.unicode)
+                    .build()?,
+            )
+        } else {
+            Regex::Str(
+                regex::RegexBuilder::new(re)
+                    .case_insensitive(flags.case_insensitive)
+                    .multi_line(flags.multi_line)
+                    .dot_matches_new_line(flags.dot_matches_new_line)
+                    .ignore_whitespace(flags.ignore_whitespace)
+                    .swap_greed(flags.swap_greed)
+                    .unicode(flags.unicode)
+                    .build()?,
+            )
+        })
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        match self {
+            Regex::Str(re) => re.as_str(),
+            Regex::Bytes(re) => re.as_str(),
+        }
+    }
+
+    pub(crate) fn captures_len(&self) -> usize {
+        match self {
+            Regex::Str(re) => re.captures_len(),
+            Regex::Bytes(re) => re.captures_len(),
+        }
+    }
+
+    pub(crate) fn capture_names(&self) -> Vec<Option<&str>> {
+        match self {
+            Regex::Str(re) => re.capture_names().collect(),
+            Regex::Bytes(re) => re.capture_names().collect(),
+        }
+    }
+
+    pub(crate) fn is_match(&self, text: &[u8]) -> bool {
+        match self {
+            Regex::Str(re) => re.is_match(as_str(text)),
+            Regex::Bytes(re) => re.is_match(text),
+        }
+    }
+
+    // Push the capture groups `groups` of the first match to `out`. Groups
+    // that did not participate in the match are pushed as empty fields.
+    // Returns false if there is no match.
+    fn extract(
+        &self,
+        text: &[u8],
+        groups: std::ops::Range<usize>,
+        out: &mut csv::ByteRecord,
+    ) -> bool {
+        match self {
+            Regex::Str(re) => match re.captures(as_str(text)) {
+                Some(captures) => {
+                    for index in groups {
+                        out.push_field(captures.get(index).map_or(b"", |m| m.as_str().as_bytes()));
+                    }
+                    true
+                }
+                None => false,
+            },
+            Regex::Bytes(re) => match re.captures(text) {
+                Some(captures) => {
+                    for index in groups {
+                        out.push_field(captures.get(index).map_or(b"", |m| m.as_bytes()));
+                    }
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    // With `split`, the pieces between the matches, otherwise the matches.
+    fn pieces<'t>(&self, text: &'t [u8], split: bool) -> Vec<&'t [u8]> {
+        match self {
+            Regex::Str(re) if split => re.split(as_str(text)).map(str::as_bytes).collect(),
+            Regex::Str(re) => re
+                .find_iter(as_str(text))
+                .map(|m| m.as_str().as_bytes())
+                .collect(),
+            Regex::Bytes(re) if split => re.split(text).collect(),
+            Regex::Bytes(re) => re.find_iter(text).map(|m| m.as_bytes()).collect(),
+        }
+    }
+
+    fn splitn<'t>(&self, text: &'t [u8], limit: usize) -> Vec<&'t [u8]> {
+        match self {
+            Regex::Str(re) => re.splitn(as_str(text), limit).map(str::as_bytes).collect(),
+            Regex::Bytes(re) => re.splitn(text, limit).collect(),
+        }
+    }
+
+    fn replace<'t>(
+        &self,
+        text: &'t [u8],
+        occurrences: Occurrences,
+        template: &Template,
+        record: &csv::ByteRecord,
+        references: &[usize],
+    ) -> Cow<'t, [u8]> {
+        // The matches before the nth one are replaced with themselves.
+        let (limit, skip) = match occurrences {
+            Occurrences::All => (0, 0),
+            Occurrences::First(n) => (n, 0),
+            Occurrences::Nth(0) => return Cow::Borrowed(text),
+            Occurrences::Nth(n) => (n, n - 1),
+        };
+        let mut count = 0;
+
+        match self {
+            Regex::Str(re) => {
+                let replacer = |captures: &regex::Captures| {
+                    count += 1;
+                    if count <= skip {
+                        return captures[0].to_string();
+                    }
+                    let mut out = Vec::new();
+                    let group = |group: &Group, out: &mut Vec<u8>| {
+                        let m = match group {
+                            Group::Index(index) => captures.get(*index),
+                            Group::Name(name) => captures.name(name),
+                        };
+                        if let Some(m) = m {
+                            out.extend_from_slice(m.as_str().as_bytes());
+                        }
+                    };
+                    template.expand(group, record, references, &mut out);
+                    String::from_utf8(out).expect("replacement should be valid utf-8")
+                };
+                match re.replacen(as_str(text), limit, replacer) {
+                    Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+                    Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+                }
+            }
+            Regex::Bytes(re) => {
+                let replacer = |captures: &regex::bytes::Captures| {
+                    count += 1;
+                    if count <= skip {
+                        return captures[0].to_vec();
+                    }
+                    let mut out = Vec::new();
+                    let group = |group: &Group, out: &mut Vec<u8>| {
+                        let m = match group {
+                            Group::Index(index) => captures.get(*index),
+                            Group::Name(name) => captures.name(name),
+                        };
+                        if let Some(m) = m {
+                            out.extend_from_slice(m.as_bytes());
+                        }
+                    };
+                    template.expand(group, record, references, &mut out);
+                    out
+                };
+                re.replacen(text, limit, replacer)
+            }
+        }
+    }
+}
+
+struct Flags {
+    case_insensitive: bool,
+    multi_line: bool,
+    dot_matches_new_line: bool,
+    ignore_whitespace: bool,
+    swap_greed: bool,
+    unicode: bool,
+}
+
+impl Flags {
+    // Returns the first unknown flag on errors.
+    fn parse(flags: &str) -> Result<Flags, char> {
+        let mut parsed = Flags {
+            case_insensitive: false,
+            multi_line: false,
+            dot_matches_new_line: false,
+            ignore_whitespace: false,
+            swap_greed: false,
+            unicode: true,
+        };
+        let mut on = true;
+
+        for flag in flags.chars() {
+            match flag {
+                '-' if on => on = false,
+                'i' => parsed.case_insensitive = on,
+                'm' => parsed.multi_line = on,
+                's' => parsed.dot_matches_new_line = on,
+                'x' => parsed.ignore_whitespace = on,
+                'U' => parsed.swap_greed = on,
+                'u' => parsed.unicode = on,
+                flag => return Err(flag),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+fn as_str(text: &[u8]) -> &str {
+    std::str::from_utf8(text).expect("record should be valid utf-8")
+}
@@ -1,68 +1,19 @@
-use std::error::Error;
+use std::fs::{self, File};
 use std::io;
+use std::iter;
+use std::path::{Path, PathBuf};
 use std::process;
 
-use csv;
-use docopt;
-use regex;
+use csvre::{
+    ColumnSelector, Condition, Dialect, Error, Mode, Replacer, ReplacerBuilder, Rule, Sniffed,
+    Unmatched,
+};
 use serde_derive::Deserialize;
 
-#[derive(Debug)]
-enum MyError {
-    ColumnNotFound,
-    Csv(csv::Error),
-    Io(io::Error),
-    Regex(regex::Error),
-    ParseInt(std::num::ParseIntError),
-}
-
-impl Error for MyError {}
-
-impl std::fmt::Display for MyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            MyError::ColumnNotFound => write!(f, "column not found"),
-            MyError::Csv(e) => e.fmt(f),
-            MyError::Io(e) => e.fmt(f),
-            MyError::Regex(e) => e.fmt(f),
-            MyError::ParseInt(e) => e.fmt(f),
-        }
-    }
-}
-
-impl From<csv::Error> for MyError {
-    fn from(error: csv::Error) -> Self {
-        if error.is_io_error() {
-            let error = error.into_kind();
-            match error {
-                csv::ErrorKind::Io(e) => MyError::Io(e),
-                _ => unreachable!(),
-            }
-        } else {
-            MyError::Csv(error)
-        }
-    }
-}
-
-impl From<io::Error> for MyError {
-    fn from(error: io::Error) -> Self {
-        MyError::Io(error)
-    }
-}
-
-impl From<regex::Error> for MyError {
-    fn from(error: regex::Error) -> Self {
-        MyError::Regex(error)
-    }
-}
+// How much of each input is inspected with --sniff.
+const SNIFF_LIMIT: usize = 64 * 1024;
 
-impl From<std::num::ParseIntError> for MyError {
-    fn from(error: std::num::ParseIntError) -> Self {
-        MyError::ParseInt(error)
-    }
-}
-
-const USAGE: &'static str = "
+const USAGE: &str = r#"
 csvre
 
 A simple tool for replacing data in CSV columns with regular
@@ -70,7 +21,10 @@ expressions.
 
 USAGE:
 
-    csvre [options] --column=COLUMN <regex> <replacement>
+    csvre [options] (--filter | --invert-match | --extract | --explode | --split) (--column=COLUMN | --column-regex=REGEX) <regex> [<input>...]
+    csvre [options] [--where=EXPR]... (--column=COLUMN | --column-regex=REGEX) <regex> <replacement> [<input>...]
+    csvre [options] [--where=EXPR]... (-e <column> <regex> <replacement>)... [<input>...]
+    csvre [options] [--where=EXPR]... --rules=FILE [<input>...]
     csvre (-h | --help)
     csvre --version
 
@@ -94,10 +48,76 @@ ARGUMENTS:
         ${name} syntax. You can also use integers to reference capture
         groups with $0 being the whole match, $1 the first group and so on.
 
-        If a capture group is not valid (name does not exist or index is
-        invalid), it is replaced with the empty string.
-
-        To insert a literal $, use $$.
+        References to capture groups that the regex does not have are
+        reported as errors before any input is read. A group that did
+        not participate in the match is replaced with the empty string.
+
+        Other fields of the same record can be referenced with
+        ${col:COLUMN}, where COLUMN is a single column name or index
+        given like with --column. For example, the regex ^$ with the
+        replacement "${col:last}, ${col:first}" fills empty fields from
+        two other columns.
+
+        A braced reference can be followed by filters that are applied
+        in order, e.g. ${1:upper} or ${name:trim:title}. The filters are
+        upper, lower, title (uppercase the first letter of each word
+        and lowercase the rest) and trim (remove surrounding
+        whitespace). Like in sed, \U and \L convert the rest of the
+        replacement to upper or lower case, until \E.
+
+        Like in a shell, ${name:-default} expands to default if the
+        group is empty or did not participate in the match, and
+        ${name:+present} expands to present if it is not empty. These
+        work with ${col:COLUMN} too, and can come after the filters,
+        e.g. ${1:trim:-none}.
+
+        To insert a literal $, use $$, and to insert a literal
+        backslash, use \\, e.g. C:\\Users\\$1. Other backslashes are
+        taken literally.
+
+    <input>...
+
+        Input files. The files are processed one after another and
+        the results are written to a single output. The header row
+        is only written once.
+
+        The headers of the rest of the files must have the same
+        column names as the first file. If the columns are in a
+        different order, they are reordered to match the first file.
+
+        If no input files are given, or the file is -, the input is
+        read from stdin.
+
+RULES:
+
+    Instead of --column, a rule can be given with the -e option
+    followed by the column, regex and replacement. The column is
+    given like with --column.
+
+    The -e option can be repeated to apply several rules in one
+    pass. The rules are applied to each record in the given
+    order, so a rule sees the result of the rules before it.
+
+    Longer lists of rules can be kept in a TOML file that is
+    given with the --rules option. The file contains an array of
+    rule tables:
+
+        [[rule]]
+        name = "phone"                  # optional
+        comment = "Strip whitespace"    # optional
+        column = "phone"                # or column = { regex = "^phone_" }
+        regex = '\s+'
+        replacement = ""
+        flags = "i"                     # optional, see (?flags)
+        into = "phone_digits"           # optional, see --into
+        after = true                    # optional, see --after
+        limit = 1                       # optional, see --limit
+        nth = 2                         # optional, instead of limit, see --nth
+        full = true                     # optional, see --full
+
+    All regular expressions are compiled before any input is
+    read, and every invalid one is reported with its rule name
+    and line.
 
 OPTIONS:
 
@@ -109,19 +129,268 @@ OPTIONS:
 
         Show the version number.
 
+    --dialect=NAME
+
+        Use a predefined dialect for input and output. One of excel,
+        rfc4180 or tsv. The excel and rfc4180 dialects are comma
+        separated and use CRLF line terminators in the output. The tsv
+        dialect is tab separated and does not use quotes at all.
+
+        The other dialect options override the settings of the
+        dialect.
+
     -d DELIM, --delimiter=DELIM
 
-        Field delimiter. This is used for both input and output.
-        [default: ,]
+        Field delimiter. This is used for both input and output,
+        unless --out-delimiter is given. The default is a comma.
+
+        Characters can also be given by name (tab, space, comma,
+        semicolon, colon or pipe) or as an escape: \t, \n, \r, \0,
+        \\ or \xNN for any byte, e.g. \x1f.
+
+        A delimiter of more than one character, e.g. ||, splits each
+        input line at every occurrence of it. In that case quotes have
+        no special meaning in the input, and the output is comma
+        separated unless --out-delimiter is given.
+
+    --delimiter-regex=REGEX
+
+        Split each input line at every match of REGEX, e.g. \s+ for
+        runs of whitespace. Like with a multi-character --delimiter,
+        quotes have no special meaning and the output is comma
+        separated unless --out-delimiter is given.
+
+    -q CHAR, --quote=CHAR
+
+        Quote character for the input. The default is ".
+
+    --escape=CHAR
+
+        Escape character for quotes in the input, e.g. a backslash.
+        By default quotes are escaped by doubling them.
+
+    --no-double-quote
+
+        Don't treat two quotes in a quoted input field as one quote.
+
+    --no-quoting
+
+        Don't treat quotes in the input specially.
+
+    --comment=CHAR
+
+        Skip input lines that start with CHAR.
+
+    --trim=MODE
+
+        Trim whitespace around input fields. One of none, headers,
+        fields or all.
+
+    --out-delimiter=DELIM
+
+        Field delimiter for the output.
+
+    --out-quote=CHAR
+
+        Quote character for the output. The default is ".
+
+    --quote-style=STYLE
+
+        When to quote fields in the output. One of always,
+        necessary, never or non-numeric. The default is necessary.
+
+    --crlf
+
+        Use CRLF line terminators in the output instead of LF.
 
     -c COLUMN, --column=COLUMN
 
-        Which column to operate on.
+        Which columns to operate on.
 
         You can either use the column name or zero based index. If
         you specify --no-headers, then you can only use the index
         here.
 
+        Several columns can be given as a comma separated list, e.g.
+        name,2,4. Ranges are written as 2-5 or first-last, and
+        3- selects everything from the fourth column onwards.
+        Negative indexes count from the end, so -1 is the last
+        column. Use * to select all columns.
+
+    --column-regex=REGEX
+
+        Operate on all columns whose name matches REGEX.
+
+        This can be used instead of --column when the exact column
+        names vary. It is an error if no column name matches. This
+        cannot be used with --no-headers.
+
+    -o FILE, --output=FILE
+
+        Write the output to FILE instead of stdout. A regular FILE is
+        only replaced once all of the output has been written, so it
+        is left as it was on errors, and it can also be one of the
+        inputs. If FILE is a symbolic link, the file it points to is
+        replaced. Pipes, devices and files under /dev, like
+        /dev/stdout, are written to directly.
+
+    --ignore-case
+
+        Match the regex case insensitively, like (?i).
+
+    --multi-line
+
+        Let ^ and $ match at the beginning and end of lines, like (?m).
+
+    --dot-matches-new-line
+
+        Let . match new lines too, like (?s).
+
+    --ignore-whitespace
+
+        Ignore whitespace in the regex and allow # comments, like (?x).
+
+    --swap-greed
+
+        Make repetitions lazy by default and greedy with ?, like (?U).
+
+    --no-unicode
+
+        Turn off Unicode support in the regex, like (?-u). Together
+        with --bytes, this lets the regex match bytes that are not
+        utf-8.
+
+        These options only apply to the rules given on the command
+        line. Rules in a rule file have their own flags.
+
+    --full
+
+        Only match the whole field, as if the regex was anchored with
+        ^ and $. This applies in all modes, so e.g. with --filter only
+        the records where the whole field matches are output.
+
+    --limit=N
+
+        Only replace the first N matches in each field.
+
+    --nth=N
+
+        Only replace the Nth match in each field, e.g. 2 for the
+        second one.
+
+    --where=EXPR
+
+        Only replace in records that meet the condition EXPR, which is
+        either COLUMN=~REGEX or COLUMN!~REGEX. The first requires that
+        REGEX matches in COLUMN, the second that it does not. COLUMN is
+        given like with --column. Other records are written unchanged.
+
+        This can be repeated, and then all of the conditions must be
+        met, e.g. --where 'currency=~^EUR$' --where 'price!~^0$'.
+
+    --any
+
+        Replace in records that meet any of the --where conditions,
+        instead of all of them.
+
+    --into=NAME
+
+        Write the result into a new column called NAME and leave the
+        original column as it is. The new column is appended after all
+        the other columns. Only a single column can be selected.
+
+    --after
+
+        With --into, insert the new column right after the original
+        column.
+
+    --filter
+
+        Instead of replacing, output only the records where the regex
+        matches in any of the selected columns. The header row is
+        kept. With several rules, a record is output if any of them
+        matches, and the replacements are not used.
+
+    -v, --invert-match
+
+        Like --filter, but output only the records that do not match.
+
+    --extract
+
+        Instead of replacing, append the capture groups of the regex as
+        new columns. The original columns are left as they are.
+
+        The new columns are named after the capture groups, e.g.
+        (?P<area>\d{3})-(?P<number>\d+) adds the columns area and
+        number. Unnamed groups are called COLUMN_N, where N is the
+        index of the group. If several columns are selected, the named
+        groups are called COLUMN_NAME. A regex without any groups
+        extracts the whole match.
+
+    --unmatched=ACTION
+
+        What --extract does with records where the regex does not
+        match. One of empty, skip or error. With empty, the new
+        columns are left empty. The default is empty.
+
+    --explode
+
+        Instead of replacing, output a copy of the record for each match
+        of the regex, with the column replaced by the match. The other
+        columns are duplicated. A record without any matches is output
+        once, with the column empty. Only a single column can be
+        selected.
+
+    --pieces
+
+        With --explode, use the regex as a separator and output a copy
+        of the record for each piece between the matches, e.g. ;\s*
+        for lists like tag1; tag2; tag3.
+
+    --split
+
+        Instead of replacing, use the regex as a separator and append
+        the pieces of the column as new columns called COLUMN_1,
+        COLUMN_2 and so on. Only a single column can be selected.
+
+        By default there are as many new columns as there are pieces
+        in the first record.
+
+    --count=N
+
+        With --split, add N new columns. If a field has more pieces,
+        the last column holds the rest of the field.
+
+    --pad
+
+        With --split, add empty fields to records that have fewer
+        pieces than there are new columns.
+
+    --drop
+
+        With --split, leave the original column out of the output.
+
+    --source-column=NAME
+
+        Add a column called NAME to the output, containing the name of
+        the input file each record came from. Records from stdin get
+        the name -.
+
+    -i, --in-place
+
+        Edit the input files in place.
+
+        Each file is processed separately and written to a temporary
+        file in the same directory, which then replaces the original.
+        If an error occurs, the original file is left untouched. If
+        an input is a symbolic link, the file it points to is
+        replaced.
+
+    --backup=SUFFIX
+
+        When editing in place, keep a copy of each original file with
+        SUFFIX appended to its name.
+
     -n, --no-headers
 
         The input does not have a header row.
@@ -129,43 +398,104 @@ OPTIONS:
         If you use this option, you can do matching against the first
         row of input.
 
+    --sniff
+
+        Guess the delimiter, the quote character and whether there is
+        a header row from the first 64 KiB of each input. The guesses
+        can be overridden with --delimiter, --quote and --no-headers.
+        Nothing is sniffed from inputs that are split with a
+        multi-character delimiter or with --delimiter-regex.
+
+    --strict-groups
+
+        Report references to capture groups that the regex does not
+        have as errors. This is always on, since replacements are
+        always validated, and the option is only accepted for scripts
+        that give it.
+
+    --verbose
+
+        Report the guesses made by --sniff on stderr.
+
     -b, --bytes
 
         Don't assume utf-8 input, work on raw bytes instead.
 
         See https://docs.rs/regex/1.1.2/regex/bytes/index.html#syntax
         for differences to the normal matching rules.
-";
+"#;
 
 #[derive(Deserialize)]
 struct Args {
-    arg_regex: String,
-    arg_replacement: String,
-    flag_delimiter: String,
+    arg_column: Vec<String>,
+    arg_regex: Vec<String>,
+    arg_replacement: Vec<String>,
+    arg_input: Vec<String>,
+    flag_dialect: String,
+    // An empty --delimiter is an error, so it is told apart from a missing
+    // one.
+    flag_delimiter: Option<String>,
+    flag_delimiter_regex: String,
+    flag_quote: String,
+    flag_escape: String,
+    flag_no_double_quote: bool,
+    flag_no_quoting: bool,
+    flag_comment: String,
+    flag_trim: String,
+    flag_out_delimiter: String,
+    flag_out_quote: String,
+    flag_quote_style: String,
+    flag_crlf: bool,
     flag_column: String,
+    flag_column_regex: String,
+    flag_where: Vec<String>,
+    flag_any: bool,
+    flag_ignore_case: bool,
+    flag_multi_line: bool,
+    flag_dot_matches_new_line: bool,
+    flag_ignore_whitespace: bool,
+    flag_swap_greed: bool,
+    flag_no_unicode: bool,
+    flag_full: bool,
+    flag_limit: String,
+    flag_nth: String,
+    flag_into: String,
+    flag_after: bool,
+    flag_filter: bool,
+    flag_invert_match: bool,
+    flag_extract: bool,
+    flag_unmatched: String,
+    flag_explode: bool,
+    flag_pieces: bool,
+    flag_split: bool,
+    flag_count: String,
+    flag_pad: bool,
+    flag_drop: bool,
+    flag_rules: String,
+    flag_output: String,
+    flag_in_place: bool,
+    flag_backup: String,
+    flag_source_column: String,
     flag_no_headers: bool,
+    flag_sniff: bool,
+    // Always on, see --strict-groups in USAGE.
+    #[allow(dead_code)]
+    flag_strict_groups: bool,
+    flag_verbose: bool,
     flag_bytes: bool,
 }
 
 fn main() {
-    match run() {
-        Ok(()) => (),
-        Err(error) => {
-            match error {
-                MyError::Io(ref error) => {
-                    if error.kind() == io::ErrorKind::BrokenPipe {
-                        return;
-                    }
-                }
-                _ => (),
-            }
-            eprintln!("error: {}", error);
-            process::exit(1);
+    if let Err(error) = run() {
+        if error.is_broken_pipe() {
+            return;
         }
+        eprintln!("error: {}", error);
+        process::exit(1);
     }
 }
 
-fn run() -> Result<(), MyError> {
+fn run() -> Result<(), Error> {
     let version = format!(
         "{}.{}.{}",
         env!("CARGO_PKG_VERSION_MAJOR"),
@@ -177,153 +507,581 @@ fn run() -> Result<(), MyError> {
         .and_then(|d| d.help(true).version(Some(version)).deserialize())
         .unwrap_or_else(|e| e.exit());
 
-    let delimiter = args.flag_delimiter.as_bytes()[0];
-    let column_str = args.flag_column;
+    if args.flag_delimiter.as_deref() == Some("") {
+        docopt::Error::Argv("--delimiter cannot be empty".to_string()).exit();
+    }
 
-    // (Ab)use Result as kind of an Either type ... :-)
+    let mut builder = ReplacerBuilder::new();
 
-    let re = if args.flag_bytes {
-        Err(regex::bytes::Regex::new(&args.arg_regex)?)
-    } else {
-        Ok(regex::Regex::new(&args.arg_regex)?)
-    };
+    if !args.flag_rules.is_empty() {
+        let path = &args.flag_rules;
+        let rules = fs::read_to_string(path)
+            .map_err(Error::from)
+            .and_then(|rules| csvre::parse_rules(&rules))
+            .map_err(|e| Error::File(path.into(), Box::new(e)))?;
+        for rule in rules {
+            builder.rule(rule);
+        }
+    }
 
-    let replacement = if args.flag_bytes {
-        Err(args.arg_replacement.as_bytes())
+    // Without -e, the rule's column comes from --column or --column-regex.
+    let columns: Vec<ColumnSelector> = if !args.arg_column.is_empty() {
+        args.arg_column
+            .iter()
+            .cloned()
+            .map(ColumnSelector::Spec)
+            .collect()
+    } else if !args.flag_column_regex.is_empty() {
+        vec![ColumnSelector::Regex(args.flag_column_regex.clone())]
     } else {
-        Ok(args.arg_replacement.as_str())
+        vec![ColumnSelector::Spec(args.flag_column.clone())]
     };
 
-    let mut reader = csv::ReaderBuilder::new()
-        .delimiter(delimiter)
-        .has_headers(!args.flag_no_headers)
-        .flexible(true)
-        .from_reader(io::stdin());
+    let limit = positive("--limit", &args.flag_limit);
+    let nth = positive("--nth", &args.flag_nth);
+    if limit.is_some() && nth.is_some() {
+        docopt::Error::Argv("--limit cannot be used with --nth".to_string()).exit();
+    }
 
-    let mut writer = csv::WriterBuilder::new()
-        .delimiter(delimiter)
-        .flexible(true)
-        .from_writer(io::stdout());
-
-    // If we have headers, and we cannot parse column as an integer,
-    // then we try to check if the column is included in the headers.
-    let column_index: usize = if reader.has_headers() {
-        reader.byte_headers()?;
-        match column_str.parse() {
-            Ok(n) => n,
-            Err(_) => {
-                if args.flag_bytes {
-                    reader
-                        .byte_headers()?
-                        .iter()
-                        .position(|x| x == column_str.as_bytes())
-                        .ok_or(MyError::ColumnNotFound)?
-                } else {
-                    reader
-                        .headers()?
-                        .iter()
-                        .position(|x| x == column_str)
-                        .ok_or(MyError::ColumnNotFound)?
-                }
-            }
+    let flags: String = [
+        (args.flag_ignore_case, "i"),
+        (args.flag_multi_line, "m"),
+        (args.flag_dot_matches_new_line, "s"),
+        (args.flag_ignore_whitespace, "x"),
+        (args.flag_swap_greed, "U"),
+        (args.flag_no_unicode, "-u"),
+    ]
+    .iter()
+    .filter(|(on, _)| *on)
+    .map(|(_, flag)| *flag)
+    .collect();
+
+    // There is no replacement when filtering.
+    let replacements = args
+        .arg_replacement
+        .iter()
+        .map(String::as_str)
+        .chain(iter::repeat(""));
+
+    for ((column, regex), replacement) in columns.into_iter().zip(&args.arg_regex).zip(replacements)
+    {
+        let mut rule = Rule::new(column, regex, replacement)
+            .flags(&flags)
+            .full(args.flag_full);
+        if let Some(n) = limit {
+            rule = rule.limit(n);
         }
+        if let Some(n) = nth {
+            rule = rule.nth(n);
+        }
+        if !args.flag_into.is_empty() {
+            rule = rule
+                .output_column(&args.flag_into)
+                .insert_after(args.flag_after);
+        }
+        builder.rule(rule);
+    }
+
+    for expr in &args.flag_where {
+        builder.condition(condition(expr));
+    }
+    builder.any(args.flag_any);
+
+    if !args.flag_into.is_empty() && args.arg_column.len() > 1 {
+        docopt::Error::Argv("--into cannot be used with several rules".to_string()).exit();
+    }
+
+    let mut modes = Vec::new();
+
+    if args.flag_filter || args.flag_invert_match {
+        modes.push((
+            "--filter",
+            Mode::Filter {
+                invert: args.flag_invert_match,
+            },
+        ));
+    }
+    if args.flag_extract {
+        let unmatched = match args.flag_unmatched.as_str() {
+            "" | "empty" => Unmatched::Empty,
+            "skip" => Unmatched::Skip,
+            "error" => Unmatched::Error,
+            action => docopt::Error::Argv(format!("unknown --unmatched action: {}", action)).exit(),
+        };
+        modes.push(("--extract", Mode::Extract { unmatched }));
+    }
+    if args.flag_explode {
+        modes.push((
+            "--explode",
+            Mode::Explode {
+                pieces: args.flag_pieces,
+            },
+        ));
+    }
+
+    if args.flag_split {
+        let count = positive("--count", &args.flag_count);
+        modes.push((
+            "--split",
+            Mode::Split {
+                count,
+                pad: args.flag_pad,
+                keep: !args.flag_drop,
+            },
+        ));
+    }
+
+    match modes[..] {
+        [] => {}
+        [(name, _)] if !args.flag_where.is_empty() => {
+            docopt::Error::Argv(format!("--where cannot be used with {}", name)).exit()
+        }
+        [(name, _)] if !args.flag_into.is_empty() => {
+            docopt::Error::Argv(format!("--into cannot be used with {}", name)).exit()
+        }
+        [(name, _)] if args.flag_after => {
+            docopt::Error::Argv(format!("--after cannot be used with {}", name)).exit()
+        }
+        [(_, mode)] => {
+            builder.mode(mode);
+        }
+        [(first, _), (second, _), ..] => {
+            docopt::Error::Argv(format!("{} cannot be used with {}", first, second)).exit()
+        }
+    }
+
+    let replacer = builder.bytes(args.flag_bytes).build()?;
+
+    let separator = separator(&args)?;
+    let separator = separator.as_ref();
+
+    if !args.flag_backup.is_empty() && !args.flag_in_place {
+        docopt::Error::Argv("--backup can only be used with --in-place".to_string()).exit();
+    }
+
+    if args.flag_in_place {
+        if args.arg_input.is_empty() || args.arg_input.iter().any(|input| input == "-") {
+            docopt::Error::Argv("--in-place needs input files".to_string()).exit();
+        }
+        if !args.flag_output.is_empty() {
+            docopt::Error::Argv("--in-place cannot be used with --output".to_string()).exit();
+        }
+        for input in &args.arg_input {
+            in_file(input, in_place(&args, &replacer, separator, input))?;
+        }
+        return Ok(());
+    }
+
+    let output_file = if args.flag_output.is_empty() || args.flag_output == "-" {
+        None
     } else {
-        column_str.parse()?
+        let output = &args.flag_output;
+        Some(in_file(
+            output,
+            open_output(Path::new(output)).map_err(Error::from),
+        )?)
+    };
+
+    let output: Box<dyn io::Write + '_> = match output_file {
+        Some(Output::File(ref file)) => Box::new(file),
+        Some(Output::Temp(ref temp, _)) => Box::new(temp.as_file()),
+        None => Box::new(io::stdout()),
     };
 
-    if args.flag_bytes {
-        run_bytes(
-            &mut reader,
-            &mut writer,
-            column_index,
-            re.as_ref().unwrap_err(),
-            replacement.unwrap_err(),
-        )?;
+    let inputs = if args.arg_input.is_empty() {
+        vec!["-".to_string()]
     } else {
-        run_string(
-            &mut reader,
-            &mut writer,
-            column_index,
-            re.as_ref().unwrap(),
-            replacement.unwrap(),
-        )?;
+        args.arg_input.clone()
+    };
+
+    // The output dialect follows the first input, which may have been
+    // sniffed.
+    let (mut reader, dialect) = in_file(&inputs[0], open_input(&args, separator, &inputs[0]))?;
+
+    let mut writer = writer_builder(&args, &dialect).from_writer(output);
+
+    let mut concat = replacer.concat(&mut writer);
+
+    if !args.flag_source_column.is_empty() {
+        concat.source_column(&args.flag_source_column);
+    }
+
+    in_file(&inputs[0], concat.run_named(&mut reader, &inputs[0]))?;
+
+    for input in &inputs[1..] {
+        let result = open_input(&args, separator, input)
+            .and_then(|(mut reader, _)| concat.run_named(&mut reader, input));
+        in_file(input, result)?;
     }
 
     writer.flush()?;
+    drop(writer);
+
+    if let Some(Output::Temp(temp, path)) = output_file {
+        in_file(&args.flag_output, persist(temp, &path))?;
+    }
 
     Ok(())
 }
 
-fn run_string<R, W>(
-    reader: &mut csv::Reader<R>,
-    writer: &mut csv::Writer<W>,
-    column_index: usize,
-    re: &regex::Regex,
-    replacement: &str,
-) -> Result<(), MyError>
-where
-    R: io::Read,
-    W: io::Write,
-{
-    let mut record_in = csv::StringRecord::new();
-    let mut record_out = csv::StringRecord::new();
-
-    if reader.has_headers() {
-        writer.write_record(reader.headers()?)?;
-    }
-
-    while reader.read_record(&mut record_in)? {
-        record_out.clear();
-
-        for index in 0..record_in.len() {
-            let field = record_in.get(index).unwrap();
-            let result = if index == column_index {
-                re.replace_all(field, replacement)
-            } else {
-                std::borrow::Cow::Borrowed(field)
-            };
-            record_out.push_field(&result);
+// Parse COLUMN=~REGEX or COLUMN!~REGEX, splitting at whichever operator
+// comes first.
+fn condition(expr: &str) -> Condition {
+    let operator = ["=~", "!~"]
+        .iter()
+        .filter_map(|op| expr.find(op).map(|index| (index, *op)))
+        .min();
+
+    match operator {
+        Some((index, op)) => Condition::new(&expr[..index], &expr[index + 2..]).negate(op == "!~"),
+        None => docopt::Error::Argv(format!(
+            "--where must be COLUMN=~REGEX or COLUMN!~REGEX: {}",
+            expr
+        ))
+        .exit(),
+    }
+}
+
+// Errors are wrapped with the name of the input file, unless it is stdin.
+fn in_file<T>(input: &str, result: Result<T, Error>) -> Result<T, Error> {
+    match result {
+        Err(e) if input != "-" => Err(Error::File(input.into(), Box::new(e))),
+        result => result,
+    }
+}
+
+// A separator that the csv crate cannot handle, given with a multi-byte
+// --delimiter or with --delimiter-regex.
+fn separator(args: &Args) -> Result<Option<regex::bytes::Regex>, Error> {
+    if !args.flag_delimiter_regex.is_empty() {
+        return Ok(Some(regex::bytes::Regex::new(&args.flag_delimiter_regex)?));
+    }
+    match args.flag_delimiter {
+        Some(ref delimiter) => match unescape("--delimiter", delimiter) {
+            ref delimiter if delimiter.len() > 1 => Ok(Some(csvre::literal_separator(delimiter))),
+            _ => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
+// Open an input, sniffing it first if requested. Returns the reader and the
+// dialect it was opened with.
+fn open_input(
+    args: &Args,
+    separator: Option<&regex::bytes::Regex>,
+    input: &str,
+) -> Result<(csv::Reader<Box<dyn io::Read>>, Dialect), Error> {
+    let mut file: Box<dyn io::Read> = if input == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(input)?)
+    };
+
+    let mut sniffed = None;
+
+    if let Some(separator) = separator {
+        let lines = io::BufReader::new(file);
+        file = Box::new(csvre::SplitReader::new(lines, separator.clone()));
+    } else if args.flag_sniff {
+        let (result, rest) = csvre::sniff_reader(file, SNIFF_LIMIT)?;
+        if args.flag_verbose {
+            eprintln!(
+                "{}: sniffed delimiter {:?}, quote {:?}, {}",
+                input,
+                result.delimiter as char,
+                result.quote as char,
+                if result.has_headers {
+                    "with headers"
+                } else {
+                    "without headers"
+                }
+            );
         }
+        file = Box::new(rest);
+        sniffed = Some(result);
+    }
+
+    let has_headers = match sniffed {
+        Some(ref sniffed) if !args.flag_no_headers => sniffed.has_headers,
+        _ => !args.flag_no_headers,
+    };
 
-        writer.write_record(&record_out)?;
+    let mut dialect = input_dialect(args, sniffed.as_ref());
+
+    // The split reader produces plain CSV.
+    if separator.is_some() {
+        dialect = Dialect {
+            comment: dialect.comment,
+            trim: dialect.trim,
+            quote_style: dialect.quote_style,
+            terminator: dialect.terminator,
+            ..Dialect::default()
+        };
     }
 
-    Ok(())
+    let reader = dialect
+        .reader_builder()
+        .has_headers(has_headers)
+        .flexible(true)
+        .from_reader(file);
+
+    Ok((reader, dialect))
+}
+
+// The dialect options override the sniffed values, which override the
+// preset given with --dialect.
+fn input_dialect(args: &Args, sniffed: Option<&Sniffed>) -> Dialect {
+    let mut input = if args.flag_dialect.is_empty() {
+        Dialect::default()
+    } else {
+        Dialect::preset(&args.flag_dialect).unwrap_or_else(|| {
+            docopt::Error::Argv(format!("unknown dialect: {}", args.flag_dialect)).exit()
+        })
+    };
+
+    if let Some(sniffed) = sniffed {
+        input.delimiter = sniffed.delimiter;
+        input.quote = sniffed.quote;
+    }
+
+    if let Some(ref delimiter) = args.flag_delimiter {
+        if let [delimiter] = unescape("--delimiter", delimiter)[..] {
+            input.delimiter = delimiter;
+        }
+    }
+    if !args.flag_quote.is_empty() {
+        input.quote = single_byte("--quote", &args.flag_quote);
+    }
+    if !args.flag_escape.is_empty() {
+        input.escape = Some(single_byte("--escape", &args.flag_escape));
+    }
+    if args.flag_no_double_quote {
+        input.double_quote = false;
+    }
+    if args.flag_no_quoting {
+        input.quoting = false;
+    }
+    if !args.flag_comment.is_empty() {
+        input.comment = Some(single_byte("--comment", &args.flag_comment));
+    }
+    if !args.flag_trim.is_empty() {
+        input.trim = match args.flag_trim.as_str() {
+            "none" => csv::Trim::None,
+            "headers" => csv::Trim::Headers,
+            "fields" => csv::Trim::Fields,
+            "all" => csv::Trim::All,
+            mode => docopt::Error::Argv(format!("unknown trim mode: {}", mode)).exit(),
+        };
+    }
+
+    input
 }
 
-fn run_bytes<R, W>(
-    reader: &mut csv::Reader<R>,
-    writer: &mut csv::Writer<W>,
-    column_index: usize,
-    re: &regex::bytes::Regex,
-    replacement: &[u8],
-) -> Result<(), MyError>
-where
-    R: io::Read,
-    W: io::Write,
-{
-    let mut record_in = csv::ByteRecord::new();
-    let mut record_out = csv::ByteRecord::new();
-
-    if reader.has_headers() {
-        writer.write_byte_record(reader.byte_headers()?)?;
-    }
-
-    while reader.read_byte_record(&mut record_in)? {
-        record_out.clear();
-
-        for index in 0..record_in.len() {
-            let field = record_in.get(index).unwrap();
-            let result = if index == column_index {
-                re.replace_all(field, replacement)
-            } else {
-                std::borrow::Cow::Borrowed(field)
-            };
-            record_out.push_field(&result);
+// Only the delimiter, line terminator and quote style of the input dialect
+// carry over to the output.
+fn writer_builder(args: &Args, input: &Dialect) -> csv::WriterBuilder {
+    let mut output = Dialect {
+        delimiter: input.delimiter,
+        quote_style: input.quote_style,
+        terminator: input.terminator,
+        ..Dialect::default()
+    };
+
+    if !args.flag_out_delimiter.is_empty() {
+        output.delimiter = single_byte("--out-delimiter", &args.flag_out_delimiter);
+    }
+    if !args.flag_out_quote.is_empty() {
+        output.quote = single_byte("--out-quote", &args.flag_out_quote);
+    }
+    if !args.flag_quote_style.is_empty() {
+        output.quote_style = match args.flag_quote_style.as_str() {
+            "always" => csv::QuoteStyle::Always,
+            "necessary" => csv::QuoteStyle::Necessary,
+            "never" => csv::QuoteStyle::Never,
+            "non-numeric" => csv::QuoteStyle::NonNumeric,
+            style => docopt::Error::Argv(format!("unknown quote style: {}", style)).exit(),
+        };
+    }
+    if args.flag_crlf {
+        output.terminator = csv::Terminator::CRLF;
+    }
+
+    let mut builder = output.writer_builder();
+    builder.flexible(true);
+    builder
+}
+
+// Returns `None` if the option was not given.
+fn positive(option: &str, value: &str) -> Option<usize> {
+    if value.is_empty() {
+        return None;
+    }
+    match value.parse() {
+        Ok(0) | Err(_) => {
+            docopt::Error::Argv(format!("{} must be a positive integer", option)).exit()
         }
+        Ok(n) => Some(n),
+    }
+}
+
+fn single_byte(option: &str, value: &str) -> u8 {
+    match unescape(option, value)[..] {
+        [byte] => byte,
+        _ => docopt::Error::Argv(format!("{} must be a single byte: {}", option, value)).exit(),
+    }
+}
 
-        writer.write_byte_record(&record_out)?;
+// Characters can be given by name, or with escapes like \t and \x1f.
+fn unescape(option: &str, value: &str) -> Vec<u8> {
+    let named = match value {
+        "tab" => Some(b'\t'),
+        "space" => Some(b' '),
+        "comma" => Some(b','),
+        "semicolon" => Some(b';'),
+        "colon" => Some(b':'),
+        "pipe" => Some(b'|'),
+        _ => None,
+    };
+    if let Some(byte) = named {
+        return vec![byte];
     }
 
+    let invalid =
+        || -> ! { docopt::Error::Argv(format!("invalid escape in {}: {}", option, value)).exit() };
+
+    let mut bytes = Vec::new();
+    let mut rest = value.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        // A trailing backslash is taken literally, so --escape \ works.
+        let (&escape, tail) = match rest.split_first() {
+            Some(split) => split,
+            None => {
+                bytes.push(byte);
+                break;
+            }
+        };
+        rest = tail;
+        bytes.push(match escape {
+            b't' => b'\t',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b'0' => 0,
+            b'\\' => b'\\',
+            b'x' if rest.len() >= 2 => {
+                let hex = std::str::from_utf8(&rest[..2]).unwrap_or_else(|_| invalid());
+                rest = &rest[2..];
+                u8::from_str_radix(hex, 16).unwrap_or_else(|_| invalid())
+            }
+            _ => invalid(),
+        });
+    }
+
+    bytes
+}
+
+// The output is written to a temporary file next to the original, which is
+// then renamed over it. If anything fails before that, the temporary file
+// is removed and the original is left untouched.
+fn in_place(
+    args: &Args,
+    replacer: &Replacer,
+    separator: Option<&regex::bytes::Regex>,
+    input: &str,
+) -> Result<(), Error> {
+    let (mut reader, dialect) = open_input(args, separator, input)?;
+
+    // A symlink is followed so that its target is replaced instead of the
+    // link.
+    let path = fs::canonicalize(input)?;
+
+    let temp = temp_file(&path)?;
+
+    let mut writer = writer_builder(args, &dialect).from_writer(temp);
+    let mut concat = replacer.concat(&mut writer);
+    if !args.flag_source_column.is_empty() {
+        concat.source_column(&args.flag_source_column);
+    }
+    concat.run_named(&mut reader, input)?;
+    let temp = writer.into_inner().map_err(|e| e.into_error())?;
+
+    if !args.flag_backup.is_empty() {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(&args.flag_backup);
+        fs::copy(&path, backup)?;
+    }
+
+    persist(temp, &path)
+}
+
+enum Output {
+    File(File),
+    // Replaces the file at the path once everything has been written.
+    Temp(tempfile::NamedTempFile, PathBuf),
+}
+
+// A regular output file is written through a temporary file that replaces it
+// only when everything has succeeded, so an error leaves it untouched and it
+// can also be one of the inputs. A symlink is followed so that its target is
+// replaced instead of the link. Other files, like pipes and devices, are
+// written to directly, and so are the files under /dev and /proc, which may
+// stand for files that are already open, like /dev/stdout.
+fn open_output(path: &Path) -> io::Result<Output> {
+    if path.starts_with("/dev") || path.starts_with("/proc") {
+        return Ok(Output::File(File::create(path)?));
+    }
+
+    let target = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => match fs::canonicalize(path) {
+            Ok(target) => target,
+            // A dangling link is left for File::create to follow.
+            Err(_) => return Ok(Output::File(File::create(path)?)),
+        },
+        _ => path.to_path_buf(),
+    };
+
+    match fs::metadata(&target) {
+        Ok(metadata) if !metadata.is_file() => Ok(Output::File(File::create(&target)?)),
+        _ => Ok(Output::Temp(temp_file(&target)?, target)),
+    }
+}
+
+// Create a temporary file next to `path` for writing its new contents.
+fn temp_file(path: &Path) -> io::Result<tempfile::NamedTempFile> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".csvre");
+
+    // Temporary files are only readable by the owner by default, but a new
+    // file should get the usual permissions.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(fs::Permissions::from_mode(0o666));
+    }
+
+    builder.tempfile_in(dir)
+}
+
+// Replace `path` with the temporary file, keeping the permissions of `path`
+// if it exists.
+fn persist(temp: tempfile::NamedTempFile, path: &Path) -> Result<(), Error> {
+    temp.as_file().sync_all()?;
+    if let Ok(metadata) = fs::metadata(path) {
+        temp.as_file().set_permissions(metadata.permissions())?;
+    }
+
+    temp.persist(path).map_err(|e| e.error)?;
+
     Ok(())
 }
@@ -0,0 +1,443 @@
+use std::ops::Range;
+
+use serde_derive::Deserialize;
+
+use crate::columns::{ColumnSelector, Columns, Selector};
+use crate::error::Error;
+use crate::template::{Group, Template};
+use crate::Regex;
+
+/// A single substitution: replace matches of a regex in some columns.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    column: ColumnSelector,
+    regex: String,
+    replacement: String,
+    flags: String,
+    output: Option<String>,
+    after: bool,
+    occurrences: Occurrences,
+    full: bool,
+    pub(crate) name: Option<String>,
+    pub(crate) line: Option<usize>,
+}
+
+/// Which matches in a field are replaced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Occurrences {
+    All,
+    First(usize),
+    Nth(usize),
+}
+
+impl Rule {
+    /// Create a rule that replaces matches of `regex` in `column` with
+    /// `replacement`.
+    ///
+    /// The column is usually given as a string, see
+    /// [`ColumnSelector::Spec`] for the syntax.
+    ///
+    /// The replacement string uses the syntax of `regex::Regex::replace`:
+    /// capture groups are referenced with `$name`, `${name}` or `$1`, and a
+    /// literal `$` is written as `$$`. Other fields of the same record are
+    /// referenced with `${col:name}` or `${col:2}`. Braced references can
+    /// be followed by the filters `upper`, `lower`, `title` and `trim`, e.g.
+    /// `${1:upper}`, and `\U`, `\L` and `\E` change the case of the rest of
+    /// the replacement like in sed. A literal backslash is written as `\\`.
+    pub fn new<C>(column: C, regex: &str, replacement: &str) -> Rule
+    where
+        C: Into<ColumnSelector>,
+    {
+        Rule {
+            column: column.into(),
+            regex: regex.to_string(),
+            replacement: replacement.to_string(),
+            flags: String::new(),
+            output: None,
+            after: false,
+            occurrences: Occurrences::All,
+            full: false,
+            name: None,
+            line: None,
+        }
+    }
+
+    /// Give the rule a name that is used in error messages.
+    pub fn name(mut self, name: &str) -> Rule {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Set regex flags for the rule, e.g. `"i"` for case insensitive
+    /// matching.
+    ///
+    /// The flags are the same that can be given inline with `(?flags)`:
+    /// `i` for case insensitive, `m` for multi-line, `s` for letting `.`
+    /// match `\n`, `x` for ignoring whitespace, `U` for swapping the meaning
+    /// of greedy and lazy repetitions, and `u` for Unicode. Flags after a
+    /// `-` are turned off, e.g. `"i-u"`.
+    pub fn flags(mut self, flags: &str) -> Rule {
+        self.flags = flags.to_string();
+        self
+    }
+
+    /// Only replace the first `n` matches in each field. Zero means all of
+    /// them, which is the default.
+    pub fn limit(mut self, n: usize) -> Rule {
+        self.occurrences = match n {
+            0 => Occurrences::All,
+            n => Occurrences::First(n),
+        };
+        self
+    }
+
+    /// Only replace the `n`th match in each field, counting from one. Zero
+    /// replaces nothing, so the fields are left as they are.
+    ///
+    /// This overrides [`Rule::limit`] and vice versa.
+    pub fn nth(mut self, n: usize) -> Rule {
+        self.occurrences = Occurrences::Nth(n);
+        self
+    }
+
+    /// Only match the whole field, as if the regex was anchored with `^`
+    /// and `$`.
+    ///
+    /// This applies in all modes, so e.g. [`Mode::Filter`] only keeps the
+    /// records where the whole field matches.
+    ///
+    /// [`Mode::Filter`]: crate::Mode::Filter
+    pub fn full(mut self, yes: bool) -> Rule {
+        self.full = yes;
+        self
+    }
+
+    /// Write the result into a new column called `name` instead of
+    /// overwriting the original column, which must be a single column.
+    ///
+    /// The new column is appended after all the other columns, unless
+    /// [`Rule::insert_after`] is used. Only used in [`Mode::Replace`].
+    ///
+    /// [`Mode::Replace`]: crate::Mode::Replace
+    pub fn output_column(mut self, name: &str) -> Rule {
+        self.output = Some(name.to_string());
+        self
+    }
+
+    /// Insert the new column given with [`Rule::output_column`] right after
+    /// the original column.
+    pub fn insert_after(mut self, yes: bool) -> Rule {
+        self.after = yes;
+        self
+    }
+
+    pub(crate) fn replacement(&self) -> &str {
+        &self.replacement
+    }
+
+    pub(crate) fn compile(&self, bytes: bool) -> Result<CompiledRule, regex::Error> {
+        // The regex is compiled on its own first, so that errors point to
+        // what was written and not to the anchors added for a full match.
+        let mut regex = Regex::with_flags(&self.regex, &self.flags, bytes)?;
+        if self.full {
+            // If the regex ends in a comment, whether the verbose mode was
+            // set with the flags or inline, the comment hides the closing
+            // parenthesis. It is then ended with a new line, which would be
+            // matched literally otherwise.
+            let anchored = format!("\\A(?:{})\\z", self.regex);
+            regex = Regex::with_flags(&anchored, &self.flags, bytes).or_else(|_| {
+                let anchored = format!("\\A(?:{}\n)\\z", self.regex);
+                Regex::with_flags(&anchored, &self.flags, bytes)
+            })?;
+        }
+
+        Ok(CompiledRule {
+            column: Selector::new(&self.column, bytes)?,
+            regex,
+            template: Template::parse(&self.replacement),
+            output: self.output.clone(),
+            after: self.after,
+            occurrences: self.occurrences,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CompiledRule {
+    column: Selector,
+    regex: Regex,
+    template: Template,
+    pub(crate) output: Option<String>,
+    pub(crate) after: bool,
+    occurrences: Occurrences,
+}
+
+impl CompiledRule {
+    // Without headers the first record is only used to find out the
+    // number of columns.
+    pub(crate) fn columns(
+        &self,
+        headers: &csv::ByteRecord,
+        has_headers: bool,
+    ) -> Result<Columns, Error> {
+        self.column.resolve(headers, has_headers)
+    }
+
+    pub(crate) fn is_match(&self, columns: &Columns, record: &csv::ByteRecord) -> bool {
+        record
+            .iter()
+            .enumerate()
+            .any(|(index, field)| columns.contains(index) && self.regex.is_match(field))
+    }
+
+    // A regex without groups extracts the whole match.
+    fn groups(&self) -> std::ops::Range<usize> {
+        match self.regex.captures_len() {
+            1 => 0..1,
+            len => 1..len,
+        }
+    }
+
+    pub(crate) fn extract_headers(
+        &self,
+        columns: &Columns,
+        headers: &csv::ByteRecord,
+        out: &mut csv::ByteRecord,
+    ) {
+        let names = self.regex.capture_names();
+        let prefix = columns.indexes().len() > 1;
+
+        for &column in columns.indexes() {
+            let header = headers.get(column).unwrap_or(b"");
+            for index in self.groups() {
+                let mut name = Vec::new();
+                match names[index] {
+                    Some(group) if !prefix => name.extend_from_slice(group.as_bytes()),
+                    Some(group) => {
+                        name.extend_from_slice(header);
+                        name.push(b'_');
+                        name.extend_from_slice(group.as_bytes());
+                    }
+                    None => {
+                        name.extend_from_slice(header);
+                        name.extend_from_slice(format!("_{}", index).as_bytes());
+                    }
+                }
+                out.push_field(&name);
+            }
+        }
+    }
+
+    // Returns false if the regex did not match in some column, in which
+    // case empty fields are pushed for it.
+    pub(crate) fn extract(
+        &self,
+        columns: &Columns,
+        record_in: &csv::ByteRecord,
+        record_out: &mut csv::ByteRecord,
+    ) -> bool {
+        let mut matched = true;
+
+        for &column in columns.indexes() {
+            let field = record_in.get(column).unwrap_or(b"");
+            if !self.regex.extract(field, self.groups(), record_out) {
+                for _ in self.groups() {
+                    record_out.push_field(b"");
+                }
+                matched = false;
+            }
+        }
+
+        matched
+    }
+
+    pub(crate) fn pieces<'t>(&self, field: &'t [u8], split: bool) -> Vec<&'t [u8]> {
+        self.regex.pieces(field, split)
+    }
+
+    pub(crate) fn splitn<'t>(&self, field: &'t [u8], limit: usize) -> Vec<&'t [u8]> {
+        self.regex.splitn(field, limit)
+    }
+
+    // Like `apply` when the conditions are not met. A new column is still
+    // added, with the original value.
+    pub(crate) fn skip(
+        &self,
+        columns: &Columns,
+        record_in: &csv::ByteRecord,
+        record_out: &mut csv::ByteRecord,
+    ) {
+        record_out.clone_from(record_in);
+        if self.output.is_some() {
+            let field = columns
+                .indexes()
+                .first()
+                .and_then(|&index| record_in.get(index))
+                .unwrap_or(b"");
+            record_out.push_field(field);
+        }
+    }
+
+    // Returns the references in the replacement to capture groups that the
+    // regex does not have, with their spans.
+    pub(crate) fn unknown_groups(&self) -> Vec<(Range<usize>, String)> {
+        let names = self.regex.capture_names();
+        self.template
+            .groups()
+            .filter_map(|(group, span)| match group {
+                Group::Index(index) if *index >= names.len() => {
+                    Some((span, format!("unknown capture group {}", index)))
+                }
+                Group::Name(name) if name.contains(':') => {
+                    Some((span, format!("invalid reference {:?}", name)))
+                }
+                Group::Name(name) if !names.contains(&Some(name.as_str())) => {
+                    Some((span, format!("unknown capture group {:?}", name)))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    // The columns referenced in the replacement.
+    pub(crate) fn references(
+        &self,
+        headers: &csv::ByteRecord,
+        has_headers: bool,
+    ) -> Result<Vec<usize>, Error> {
+        self.template.references(headers, has_headers)
+    }
+
+    pub(crate) fn apply(
+        &self,
+        columns: &Columns,
+        references: &[usize],
+        record_in: &csv::ByteRecord,
+        record_out: &mut csv::ByteRecord,
+    ) {
+        let replace = |field| {
+            self.regex.replace(
+                field,
+                self.occurrences,
+                &self.template,
+                record_in,
+                references,
+            )
+        };
+
+        record_out.clear();
+
+        if self.output.is_some() {
+            record_out.extend(record_in);
+            let field = columns
+                .indexes()
+                .first()
+                .and_then(|&index| record_in.get(index))
+                .unwrap_or(b"");
+            record_out.push_field(&replace(field));
+            return;
+        }
+
+        for (index, field) in record_in.iter().enumerate() {
+            if columns.contains(index) {
+                record_out.push_field(&replace(field));
+            } else {
+                record_out.push_field(field);
+            }
+        }
+    }
+}
+
+/// Parse rules from a TOML document.
+///
+/// The document contains an array of `rule` tables which are applied in
+/// order:
+///
+/// ```toml
+/// [[rule]]
+/// name = "phone"                  # optional, used in error messages
+/// comment = "Strip whitespace"    # optional, ignored
+/// column = "phone"                # or column = { regex = "^phone_" }
+/// regex = '\s+'
+/// replacement = ""
+/// flags = "i"                     # optional
+/// into = "phone_digits"           # optional, see Rule::output_column
+/// after = true                    # optional, see Rule::insert_after
+/// limit = 1                       # optional, see Rule::limit
+/// nth = 2                         # optional, instead of limit, see Rule::nth
+/// full = true                     # optional, see Rule::full
+/// ```
+///
+/// The regular expressions are not compiled here. The line of each rule is
+/// recorded so that [`ReplacerBuilder::build`](crate::ReplacerBuilder::build)
+/// can point to the offending rules.
+pub fn parse_rules(toml: &str) -> Result<Vec<Rule>, Error> {
+    let file: RuleFile = toml::from_str(toml)?;
+
+    let rules = file
+        .rule
+        .into_iter()
+        .map(|entry| {
+            let line = toml[..entry.regex.start()].matches('\n').count() + 1;
+            match (entry.limit, entry.nth) {
+                (Some(_), Some(_)) => {
+                    return Err(Error::InvalidRule(line, "limit cannot be used with nth"));
+                }
+                (_, Some(0)) => return Err(Error::InvalidRule(line, "nth must be at least 1")),
+                _ => {}
+            }
+            let column = match entry.column {
+                ColumnEntry::Spec(spec) => ColumnSelector::Spec(spec),
+                ColumnEntry::Regex { regex } => ColumnSelector::Regex(regex),
+            };
+            let mut rule = Rule::new(column, entry.regex.get_ref(), &entry.replacement)
+                .flags(&entry.flags)
+                .insert_after(entry.after)
+                .limit(entry.limit.unwrap_or(0))
+                .full(entry.full);
+            if let Some(nth) = entry.nth {
+                rule = rule.nth(nth);
+            }
+            rule.output = entry.into;
+            rule.name = entry.name;
+            rule.line = Some(line);
+            Ok(rule)
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(rules)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    #[serde(default)]
+    rule: Vec<RuleEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleEntry {
+    name: Option<String>,
+    #[serde(rename = "comment")]
+    _comment: Option<String>,
+    column: ColumnEntry,
+    regex: toml::Spanned<String>,
+    replacement: String,
+    #[serde(default)]
+    flags: String,
+    into: Option<String>,
+    #[serde(default)]
+    after: bool,
+    limit: Option<usize>,
+    nth: Option<usize>,
+    #[serde(default)]
+    full: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ColumnEntry {
+    Spec(String),
+    Regex { regex: String },
+}
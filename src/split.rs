@@ -0,0 +1,89 @@
+use std::io;
+
+/// Splits lines at a separator that the CSV reader cannot handle, like a
+/// multi-character string or a regular expression.
+///
+/// Each line of the underlying reader is split into fields at every match of
+/// the separator. The fields are written out as CSV with a comma as the
+/// delimiter, so that the result can be read with a default `csv::Reader`.
+/// Quotes have no special meaning in the input, and a field cannot span
+/// several lines.
+#[derive(Debug)]
+pub struct SplitReader<R> {
+    reader: R,
+    separator: regex::bytes::Regex,
+    line: Vec<u8>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: io::BufRead> SplitReader<R> {
+    /// Split the lines of `reader` at every match of `separator`.
+    pub fn new(reader: R, separator: regex::bytes::Regex) -> SplitReader<R> {
+        SplitReader {
+            reader,
+            separator,
+            line: Vec::new(),
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    // Returns false at the end of input.
+    fn fill(&mut self) -> io::Result<bool> {
+        self.line.clear();
+        self.buf.clear();
+        self.pos = 0;
+
+        if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+            return Ok(false);
+        }
+
+        let mut line = &self.line[..];
+        if line.ends_with(b"\n") {
+            line = &line[..line.len() - 1];
+        }
+        if line.ends_with(b"\r") {
+            line = &line[..line.len() - 1];
+        }
+
+        for (index, field) in self.separator.split(line).enumerate() {
+            if index > 0 {
+                self.buf.push(b',');
+            }
+            if field.iter().any(|&b| b == b',' || b == b'"' || b == b'\r') {
+                self.buf.push(b'"');
+                for &b in field {
+                    if b == b'"' {
+                        self.buf.push(b'"');
+                    }
+                    self.buf.push(b);
+                }
+                self.buf.push(b'"');
+            } else {
+                self.buf.extend_from_slice(field);
+            }
+        }
+        self.buf.push(b'\n');
+
+        Ok(true)
+    }
+}
+
+impl<R: io::BufRead> io::Read for SplitReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() && !self.fill()? {
+            return Ok(0);
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Create a regular expression that matches `separator` literally.
+pub fn literal_separator(separator: &[u8]) -> regex::bytes::Regex {
+    let pattern: String = separator.iter().map(|b| format!("\\x{:02x}", b)).collect();
+    regex::bytes::Regex::new(&format!("(?-u){}", pattern)).expect("escaped separator is valid")
+}
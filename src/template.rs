@@ -0,0 +1,333 @@
+use std::ops::Range;
+
+use crate::columns::Selector;
+use crate::error::Error;
+
+/// A parsed replacement string.
+///
+/// The syntax is that of `regex::Regex::replace`, extended with
+/// references to other fields of the same record: `${col:name}` expands to
+/// the field in the column `name`, which is given like a single column in a
+/// [`ColumnSelector::Spec`](crate::ColumnSelector::Spec).
+///
+/// A braced reference can be followed by filters, e.g. `${1:trim:upper}`,
+/// and then like in a shell, by a default value for when it is empty,
+/// `${1:-default}`, or a value for when it is not, `${1:+present}`.
+/// Like in sed, `\U` and `\L` convert the rest of the expansion to upper or
+/// lower case, until `\E`. A literal backslash is written as `\\`.
+#[derive(Clone, Debug)]
+pub(crate) struct Template {
+    pieces: Vec<Piece>,
+    columns: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+enum Piece {
+    Literal(Vec<u8>),
+    // The span of the reference in the replacement is kept for errors.
+    Group(Group, Vec<Filter>, Option<Alternative>, Range<usize>),
+    // An index to `Template::columns`.
+    Column(usize, Vec<Filter>, Option<Alternative>),
+    // Start or end (`None`) a case conversion.
+    Case(Option<Filter>),
+}
+
+#[derive(Clone, Debug)]
+enum Alternative {
+    // Used if the value is empty.
+    Default(Vec<u8>),
+    // Used instead of the value if it is not empty.
+    Present(Vec<u8>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Filter {
+    Upper,
+    Lower,
+    Title,
+    Trim,
+}
+
+impl Filter {
+    fn parse(name: &str) -> Option<Filter> {
+        match name {
+            "upper" => Some(Filter::Upper),
+            "lower" => Some(Filter::Lower),
+            "title" => Some(Filter::Title),
+            "trim" => Some(Filter::Trim),
+            _ => None,
+        }
+    }
+
+    // Non utf-8 text, which is only possible in bytes mode, is converted
+    // as ASCII.
+    fn apply(self, text: &[u8]) -> Vec<u8> {
+        let text = match std::str::from_utf8(text) {
+            Ok(text) => text,
+            Err(_) => return self.apply_ascii(text),
+        };
+
+        match self {
+            Filter::Upper => text.to_uppercase().into_bytes(),
+            Filter::Lower => text.to_lowercase().into_bytes(),
+            Filter::Title => {
+                let mut out = String::with_capacity(text.len());
+                let mut start = true;
+                for c in text.chars() {
+                    if start {
+                        out.extend(c.to_uppercase());
+                    } else {
+                        out.extend(c.to_lowercase());
+                    }
+                    start = !c.is_alphanumeric();
+                }
+                out.into_bytes()
+            }
+            Filter::Trim => text.trim().as_bytes().to_vec(),
+        }
+    }
+
+    fn apply_ascii(self, text: &[u8]) -> Vec<u8> {
+        match self {
+            Filter::Upper => text.to_ascii_uppercase(),
+            Filter::Lower => text.to_ascii_lowercase(),
+            Filter::Title => {
+                let mut start = true;
+                text.iter()
+                    .map(|&b| {
+                        let c = if start {
+                            b.to_ascii_uppercase()
+                        } else {
+                            b.to_ascii_lowercase()
+                        };
+                        start = !b.is_ascii_alphanumeric();
+                        c
+                    })
+                    .collect()
+            }
+            Filter::Trim => text.trim_ascii().to_vec(),
+        }
+    }
+}
+
+/// A reference to a capture group.
+#[derive(Clone, Debug)]
+pub(crate) enum Group {
+    Index(usize),
+    Name(String),
+}
+
+impl Template {
+    pub(crate) fn parse(replacement: &str) -> Template {
+        let mut template = Template {
+            pieces: Vec::new(),
+            columns: Vec::new(),
+        };
+        let mut literal = Vec::new();
+        let mut rest = replacement;
+
+        while let Some(start) = rest.find(['$', '\\']) {
+            literal.extend_from_slice(&rest.as_bytes()[..start]);
+
+            if rest[start..].starts_with('\\') {
+                let case = match rest.as_bytes().get(start + 1) {
+                    Some(b'U') => Some(Filter::Upper),
+                    Some(b'L') => Some(Filter::Lower),
+                    Some(b'E') => None,
+                    Some(b'\\') => {
+                        literal.push(b'\\');
+                        rest = &rest[start + 2..];
+                        continue;
+                    }
+                    _ => {
+                        literal.push(b'\\');
+                        rest = &rest[start + 1..];
+                        continue;
+                    }
+                };
+                if !literal.is_empty() {
+                    template.pieces.push(Piece::Literal(literal.split_off(0)));
+                }
+                template.pieces.push(Piece::Case(case));
+                rest = &rest[start + 2..];
+                continue;
+            }
+
+            rest = &rest[start + 1..];
+
+            if let Some(tail) = rest.strip_prefix('$') {
+                literal.push(b'$');
+                rest = tail;
+                continue;
+            }
+
+            // Like in the regex crate, anything that is not a valid
+            // reference is taken literally.
+            let (reference, tail) = match rest.strip_prefix('{') {
+                Some(braced) => match braced.find('}') {
+                    Some(end) => (&braced[..end], &braced[end + 1..]),
+                    None => ("", rest),
+                },
+                None => {
+                    let end = rest
+                        .find(|c: char| !(c == '_' || c.is_ascii_alphanumeric()))
+                        .unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+
+            if reference.is_empty() {
+                literal.push(b'$');
+                continue;
+            }
+            let span = replacement.len() - rest.len() - 1..replacement.len() - tail.len();
+            rest = tail;
+
+            if !literal.is_empty() {
+                template.pieces.push(Piece::Literal(literal.split_off(0)));
+            }
+
+            let piece = template.reference(reference, span);
+            template.pieces.push(piece);
+        }
+
+        literal.extend_from_slice(rest.as_bytes());
+        if !literal.is_empty() {
+            template.pieces.push(Piece::Literal(literal));
+        }
+
+        template
+    }
+
+    // Parse the contents of a reference: a group or a column followed by
+    // filters and an alternative value. If some of the filters are
+    // unknown, the reference is taken as the name of a group or a column,
+    // which is then reported as unknown.
+    fn reference(&mut self, reference: &str, span: Range<usize>) -> Piece {
+        let (column, rest) = match reference.strip_prefix("col:") {
+            Some(rest) => (true, rest),
+            None => (false, reference),
+        };
+
+        let split = [":-", ":+"].iter().filter_map(|op| rest.find(op)).min();
+        let (rest, alternative) = match split {
+            Some(index) => {
+                let value = rest.as_bytes()[index + 2..].to_vec();
+                let alternative = match &rest[index..index + 2] {
+                    ":-" => Alternative::Default(value),
+                    _ => Alternative::Present(value),
+                };
+                (&rest[..index], Some(alternative))
+            }
+            None => (rest, None),
+        };
+
+        let mut parts = rest.split(':');
+        let name = parts.next().unwrap_or("");
+        let (name, filters) = match parts.map(Filter::parse).collect() {
+            Some(filters) => (name, filters),
+            None => (rest, Vec::new()),
+        };
+
+        if column {
+            self.columns.push(name.to_string());
+            return Piece::Column(self.columns.len() - 1, filters, alternative);
+        }
+
+        let group = match name.parse() {
+            Ok(index) => Group::Index(index),
+            Err(_) => Group::Name(name.to_string()),
+        };
+        Piece::Group(group, filters, alternative, span)
+    }
+
+    /// The capture groups referenced in the template, with their spans in
+    /// the replacement.
+    pub(crate) fn groups(&self) -> impl Iterator<Item = (&Group, Range<usize>)> {
+        self.pieces.iter().filter_map(|piece| match piece {
+            Piece::Group(group, _, _, span) => Some((group, span.clone())),
+            _ => None,
+        })
+    }
+
+    /// Find the indexes of the columns referenced with `${col:...}`.
+    pub(crate) fn references(
+        &self,
+        headers: &csv::ByteRecord,
+        has_headers: bool,
+    ) -> Result<Vec<usize>, Error> {
+        self.columns
+            .iter()
+            .map(|spec| {
+                let columns = Selector::Spec(spec.clone()).resolve(headers, has_headers)?;
+                match columns.indexes() {
+                    [index] => Ok(*index),
+                    _ => Err(Error::SingleColumn),
+                }
+            })
+            .collect()
+    }
+
+    /// Expand the template into `out`.
+    ///
+    /// `group` appends the value of a capture group to the output, and the
+    /// fields of the referenced columns are taken from `record`.
+    pub(crate) fn expand<F>(
+        &self,
+        mut group: F,
+        record: &csv::ByteRecord,
+        references: &[usize],
+        out: &mut Vec<u8>,
+    ) where
+        F: FnMut(&Group, &mut Vec<u8>),
+    {
+        let mut case = None;
+
+        for piece in &self.pieces {
+            let start = out.len();
+
+            match piece {
+                Piece::Literal(literal) => out.extend_from_slice(literal),
+                Piece::Group(g, filters, alternative, _) => {
+                    group(g, out);
+                    filter(out, start, filters);
+                    alternate(out, start, alternative);
+                }
+                Piece::Column(n, filters, alternative) => {
+                    out.extend_from_slice(record.get(references[*n]).unwrap_or(b""));
+                    filter(out, start, filters);
+                    alternate(out, start, alternative);
+                }
+                Piece::Case(filter) => case = *filter,
+            }
+
+            if let Some(case) = case {
+                filter(out, start, &[case]);
+            }
+        }
+    }
+}
+
+// Replace the end of `out` from `start` with the alternative value, if
+// there is one and it applies.
+fn alternate(out: &mut Vec<u8>, start: usize, alternative: &Option<Alternative>) {
+    match alternative {
+        Some(Alternative::Default(value)) if out.len() == start => {
+            out.extend_from_slice(value);
+        }
+        Some(Alternative::Present(value)) if out.len() > start => {
+            out.truncate(start);
+            out.extend_from_slice(value);
+        }
+        _ => {}
+    }
+}
+
+// Apply `filters` to the end of `out` from `start`.
+fn filter(out: &mut Vec<u8>, start: usize, filters: &[Filter]) {
+    for filter in filters {
+        let text = filter.apply(&out[start..]);
+        out.truncate(start);
+        out.extend_from_slice(&text);
+    }
+}
@@ -1,9 +1,12 @@
 use std::env;
 use std::ffi::OsStr;
+use std::fs;
 use std::io::{self, Write};
 use std::path::PathBuf;
 use std::process::{self, Command, Stdio};
 
+use tempfile::{NamedTempFile, TempPath};
+
 fn xsvre_exe() -> io::Result<PathBuf> {
     let mut exe = env::current_exe()?;
     exe.pop();
@@ -32,10 +35,16 @@ where
     child.wait_with_output().unwrap()
 }
 
+fn temp_file(contents: &[u8]) -> TempPath {
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(contents).unwrap();
+    file.into_temp_path()
+}
+
 #[test]
 fn numeric_column() {
     let output = command(
-        &["-c", "1", "\\s+", ""],
+        ["-c", "1", "\\s+", ""],
         b"\
 column1,column2,column3
 foo,bar,baz
@@ -60,7 +69,7 @@ ipsum,dolor,sit
 #[test]
 fn named_column() {
     let output = command(
-        &["-c", "column2", "\\s+", ""],
+        ["-c", "column2", "\\s+", ""],
         b"\
 column1,column2,column3
 foo,bar,baz
@@ -85,7 +94,7 @@ ipsum,dolor,sit
 #[test]
 fn named_column_without_headers_fails() {
     let output = command(
-        &["-c", "column2", "-n", "\\s+", ""],
+        ["-c", "column2", "-n", "\\s+", ""],
         b"\
 column1,column2,column3
 foo,bar,baz
@@ -100,7 +109,7 @@ ipsum,dolor,sit
 #[test]
 fn no_headers() {
     let output = command(
-        &["-c", "1", "-n", "\\w+", "HELLO"],
+        ["-c", "1", "-n", "\\w+", "HELLO"],
         b"\
 column1,column2,column3
 foo,bar,baz
@@ -125,7 +134,7 @@ ipsum,HELLO,sit
 #[test]
 fn change_delimiter() {
     let output = command(
-        &["-c", "1", "-d", ";", "\\s+", ""],
+        ["-c", "1", "-d", ";", "\\s+", ""],
         b"\
 column1;column2;column3
 foo;bar;baz
@@ -150,7 +159,7 @@ ipsum;dolor;sit
 #[test]
 fn byte_mode() {
     let output = command(
-        &["-c", "1", "-b", "(?-u)\\s+", ""],
+        ["-c", "1", "-b", "(?-u)\\s+", ""],
         b"\
 column1,column2,column3
 foo,\0ar,baz
@@ -171,3 +180,1386 @@ ipsum,dolor,sit
         output.stdout.as_slice()
     );
 }
+
+#[test]
+fn column_list() {
+    let output = command(
+        ["-c", "column1,2", "o", "0"],
+        b"\
+column1,column2,column3
+foo,bar,baz
+frob,n i z,lorem
+",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "\
+column1,column2,column3
+f00,bar,baz
+fr0b,n i z,l0rem
+",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn column_ranges() {
+    let input = b"\
+a,b,c,d,e
+1,2,3,4,5
+";
+
+    let output = command(["-c", "1-3", "\\d", "x"], input);
+    assert!(output.status.success());
+    assert_eq!(
+        "a,b,c,d,e\n1,x,x,x,5\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    let output = command(["-c", "c-", "\\d", "x"], input);
+    assert!(output.status.success());
+    assert_eq!(
+        "a,b,c,d,e\n1,2,x,x,x\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    let output = command(["-c", "-2,0", "\\d", "x"], input);
+    assert!(output.status.success());
+    assert_eq!(
+        "a,b,c,d,e\nx,2,3,x,5\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn all_columns() {
+    let output = command(
+        ["-c", "*", "-n", "o", "0"],
+        b"\
+foo,boo
+moo,zoo
+",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "f00,b00\nm00,z00\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn negative_column_out_of_range_fails() {
+    let output = command(["-c", "-4", "o", "0"], b"a,b,c\n1,2,3\n");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn column_regex() {
+    let output = command(
+        ["--column-regex", "^phone_", "\\D", ""],
+        b"\
+name,phone_home,phone_work
+foo,555-123,(555) 456
+",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "\
+name,phone_home,phone_work
+foo,555123,555456
+",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn column_regex_without_matches_fails() {
+    let output = command(
+        ["--column-regex", "^fax", "\\D", ""],
+        b"name,phone_home\nfoo,555-123\n",
+    );
+
+    assert!(!output.status.success());
+}
+
+#[test]
+fn multiple_rules() {
+    let output = command(
+        [
+            "-e", "column1", "o", "0", "-e", "1-2", "\\s+", "_", "-e", "column3", "_", "-",
+        ],
+        b"\
+column1,column2,column3
+foo,bar,baz
+frob,n i z,lo rem
+",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "\
+column1,column2,column3
+f00,bar,baz
+fr0b,n_i_z,lo-rem
+",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn rules_file() {
+    let rules = temp_file(
+        br#"
+[[rule]]
+name = "zeroes"
+comment = "Replace all o's with zeroes"
+column = "column1"
+regex = 'O'
+replacement = "0"
+flags = "i"
+
+[[rule]]
+column = { regex = "^column[23]$" }
+regex = '\s+'
+replacement = "_"
+"#,
+    );
+
+    let output = command(
+        [OsStr::new("--rules"), rules.as_os_str()],
+        b"\
+column1,column2,column3
+foo,bar,b a z
+frob,n i z,lorem
+",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "\
+column1,column2,column3
+f00,bar,b_a_z
+fr0b,n_i_z,lorem
+",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn rules_file_reports_all_invalid_rules() {
+    let rules = temp_file(
+        br#"
+[[rule]]
+name = "first"
+column = "column1"
+regex = '('
+replacement = ""
+
+[[rule]]
+column = "column2"
+regex = 'ok'
+replacement = ""
+
+[[rule]]
+column = "column3"
+regex = '[z'
+replacement = ""
+"#,
+    );
+
+    let output = Command::new(xsvre_exe().unwrap())
+        .arg("--rules")
+        .arg(&rules)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("rule \"first\" on line 5"));
+    assert!(stderr.contains("rule #3 on line 15"));
+}
+
+#[test]
+fn input_files() {
+    let first = temp_file(b"a,b\n1,x y\n");
+    let second = temp_file(b"a,b\n2,z w\n");
+
+    let output = command(
+        [
+            OsStr::new("-c"),
+            OsStr::new("b"),
+            OsStr::new(" "),
+            OsStr::new("_"),
+            first.as_os_str(),
+            OsStr::new("-"),
+            second.as_os_str(),
+        ],
+        b"a,b\n3,q q\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "a,b\n1,x_y\n3,q_q\n2,z_w\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn output_file() {
+    let path = temp_file(b"");
+
+    let output = command(
+        [
+            OsStr::new("-c"),
+            OsStr::new("b"),
+            OsStr::new("-o"),
+            path.as_os_str(),
+            OsStr::new(" "),
+            OsStr::new("_"),
+        ],
+        b"a,b\n1,x y\n",
+    );
+
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+
+    assert_eq!("a,b\n1,x_y\n", fs::read_to_string(&path).unwrap());
+}
+
+#[test]
+fn missing_input_file_is_named_in_error() {
+    let path = env::temp_dir().join("csvre-test-does-not-exist.csv");
+
+    let output = Command::new(xsvre_exe().unwrap())
+        .args(["-c", "b", " ", "_"])
+        .arg(&path)
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(&*path.to_string_lossy()));
+}
+
+#[test]
+fn in_place() {
+    let path = temp_file(b"a,b\n1,x y\n");
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+
+    let output = command(
+        [
+            OsStr::new("-i"),
+            OsStr::new("--backup=.bak"),
+            OsStr::new("-c"),
+            OsStr::new("b"),
+            OsStr::new(" "),
+            OsStr::new("_"),
+            path.as_os_str(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+
+    assert_eq!("a,b\n1,x_y\n", fs::read_to_string(&path).unwrap());
+    assert_eq!("a,b\n1,x y\n", fs::read_to_string(&backup).unwrap());
+
+    fs::remove_file(backup).unwrap();
+}
+
+#[test]
+fn in_place_error_keeps_original() {
+    let path = temp_file(b"a,b\n1,x y\n");
+
+    let output = command(
+        [
+            OsStr::new("-i"),
+            OsStr::new("-c"),
+            OsStr::new("c"),
+            OsStr::new(" "),
+            OsStr::new("_"),
+            path.as_os_str(),
+        ],
+        b"",
+    );
+
+    assert!(!output.status.success());
+
+    assert_eq!("a,b\n1,x y\n", fs::read_to_string(&path).unwrap());
+}
+
+#[test]
+fn input_files_with_reordered_headers() {
+    let first = temp_file(b"a,b\n1,x y\n");
+    let second = temp_file(b"b,a\nz w,2\n");
+
+    let output = command(
+        [
+            OsStr::new("-c"),
+            OsStr::new("b"),
+            OsStr::new("--source-column=source_file"),
+            OsStr::new(" "),
+            OsStr::new("_"),
+            first.as_os_str(),
+            second.as_os_str(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        format!(
+            "a,b,source_file\n1,x_y,{}\n2,z_w,{}\n",
+            first.display(),
+            second.display()
+        ),
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn input_files_with_mismatching_headers_fail() {
+    let first = temp_file(b"a,b\n1,x y\n");
+    let second = temp_file(b"a,c\n2,z w\n");
+
+    let output = command(
+        [
+            OsStr::new("-c"),
+            OsStr::new("b"),
+            OsStr::new(" "),
+            OsStr::new("_"),
+            first.as_os_str(),
+            second.as_os_str(),
+        ],
+        b"",
+    );
+
+    assert!(!output.status.success());
+}
+
+#[test]
+fn output_dialect() {
+    let output = command(
+        [
+            "-c",
+            "1",
+            "-d",
+            ";",
+            "--out-delimiter",
+            ",",
+            "--quote-style",
+            "non-numeric",
+            "--crlf",
+            "\\s+",
+            "",
+        ],
+        b"\
+column1;column2
+1;n i z
+2;a,b
+",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "\
+\"column1\",\"column2\"\r
+1,\"niz\"\r
+2,\"a,b\"\r
+",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn output_quote() {
+    let output = command(
+        ["-c", "1", "--out-quote", "'", "x", "y"],
+        b"column1,column2\n1,\"a,x\"\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "column1,column2\n1,'a,y'\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn input_dialect() {
+    let output = command(
+        [
+            "-c",
+            "b",
+            "-d",
+            ";",
+            "-q",
+            "'",
+            "--escape",
+            "\\",
+            "--no-double-quote",
+            "--comment",
+            "#",
+            "--trim",
+            "all",
+            "\\s+",
+            "_",
+        ],
+        b"\
+# comment
+a ; b
+1 ;'x; \\'y\\''
+",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "a;b\n1;\"x;_'y'\"\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn tsv_dialect() {
+    let output = command(
+        ["-c", "b", "--dialect", "tsv", "\\s+", "_"],
+        b"a\tb\n1\t\"x y\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!("a\tb\n1\t\"x_y\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn named_and_escaped_delimiters() {
+    let output = command(["-d", "tab", "-c", "b", "x", "y"], b"a\tb\n1\tx\n");
+
+    assert!(output.status.success());
+    assert_eq!("a\tb\n1\ty\n", String::from_utf8_lossy(&output.stdout));
+
+    let output = command(["-d", "\\x1f", "-c", "b", "x", "y"], b"a\x1fb\n1\x1fx\n");
+
+    assert!(output.status.success());
+    assert_eq!("a\x1fb\n1\x1fy\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn multi_character_delimiter() {
+    let output = command(["-d", "||", "-c", "b", "x", "y"], b"a||b||c\n1||x,\"x||3\n");
+
+    assert!(output.status.success());
+    assert_eq!(
+        "a,b,c\n1,\"y,\"\"y\",3\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn delimiter_regex() {
+    let output = command(
+        [
+            "--delimiter-regex",
+            "\\s+",
+            "--out-delimiter",
+            "|",
+            "-c",
+            "b",
+            "x",
+            "y",
+        ],
+        b"a  b\tc\n1 x   3\n",
+    );
+
+    assert!(output.status.success());
+    assert_eq!("a|b|c\n1|y|3\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn invalid_delimiter_fails() {
+    let output = command(["-d", "\\q", "-c", "b", "x", "y"], b"a,b\n");
+
+    assert!(!output.status.success());
+}
+
+#[test]
+fn sniff() {
+    let output = Command::new(xsvre_exe().unwrap())
+        .args(["--sniff", "--verbose", "-c", "1", "\\s+", "_"])
+        .arg(temp_file(b"1|x y\n2|\"a|b c\"\n3|z w\n").as_os_str())
+        .output()
+        .unwrap();
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "1|x_y\n2|\"a|b_c\"\n3|z_w\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("delimiter '|'"));
+    assert!(stderr.contains("without headers"));
+}
+
+#[test]
+fn sniff_headers_and_quote() {
+    let output = command(
+        ["--sniff", "-c", "value", "\\s+", "_"],
+        b"id;value\n1;'x y'\n2;'a;b c'\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "id;value\n1;x_y\n2;\"a;b_c\"\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn filter() {
+    let output = command(
+        ["--filter", "-c", "b", "^x"],
+        b"a,b\n1,\"x\ny\"\n2,z\n3,xx\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "a,b\n1,\"x\ny\"\n3,xx\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn invert_match() {
+    let output = command(["-v", "-c", "a,b", "x"], b"a,b\n1,x\nx,2\n3,4\n");
+
+    assert!(output.status.success());
+
+    assert_eq!("a,b\n3,4\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn filter_bytes() {
+    let output = command(
+        ["--filter", "--bytes", "-c", "0", "(?-u)\\xff"],
+        b"a\nfoo\nb\xffr\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(b"a\nb\xffr\n", &output.stdout[..]);
+}
+
+#[test]
+fn extract() {
+    let output = command(
+        [
+            "--extract",
+            "-c",
+            "phone",
+            "(?P<area>\\d{3})-(?P<number>\\d+)",
+        ],
+        b"name,phone\nfoo,555-1234\nbar,none\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "name,phone,area,number\nfoo,555-1234,555,1234\nbar,none,,\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn extract_several_columns() {
+    let output = command(
+        ["--extract", "-c", "a,b", "(\\d)(?P<x>\\d)"],
+        b"a,b\n12,34\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "a,b,a_1,a_x,b_1,b_x\n12,34,1,2,3,4\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn extract_unmatched() {
+    let input = b"a\nx1\ny\n";
+
+    let output = command(
+        ["--extract", "--unmatched", "skip", "-c", "a", "\\d"],
+        input,
+    );
+
+    assert!(output.status.success());
+    assert_eq!("a,a_0\nx1,1\n", String::from_utf8_lossy(&output.stdout));
+
+    let output = command(
+        ["--extract", "--unmatched", "error", "-c", "a", "\\d"],
+        input,
+    );
+
+    assert!(!output.status.success());
+}
+
+#[test]
+fn explode() {
+    let output = command(
+        ["--explode", "-c", "tags", "\\d+"],
+        b"id,tags\n1,a1b22\n2,none\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "id,tags\n1,1\n1,22\n2,\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn explode_pieces() {
+    let output = command(
+        [
+            "--explode",
+            "--pieces",
+            "--source-column",
+            "src",
+            "-c",
+            "1",
+            ";\\s*",
+        ],
+        b"id,tags,n\n1,\"a; b;c\",x\n2,d,y\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "id,tags,n,src\n1,a,x,-\n1,b,x,-\n1,c,x,-\n2,d,y,-\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn explode_several_columns_fails() {
+    let output = command(["--explode", "-c", "*", "\\d"], b"a,b\n1,2\n");
+
+    assert!(!output.status.success());
+}
+
+#[test]
+fn split() {
+    let output = command(
+        ["--split", "-c", "name", " "],
+        b"id,name\n1,a b c\n2,d e\n3,f g h i\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "id,name,name_1,name_2,name_3\n1,a b c,a,b,c\n2,d e,d,e\n3,f g h i,f,g,h i\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn split_count_pad_drop() {
+    let output = command(
+        [
+            "--split",
+            "--count",
+            "2",
+            "--pad",
+            "--drop",
+            "-c",
+            "name",
+            "\\s*,\\s*",
+        ],
+        b"id,name,x\n1,\"a, b, c\",y\n2,d,z\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "id,x,name_1,name_2\n1,y,a,\"b, c\"\n2,z,d,\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn into_new_column() {
+    let output = command(
+        ["-c", "phone", "--into", "digits", "\\D", ""],
+        b"name,phone,x\nfoo,555 12,y\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "name,phone,x,digits\nfoo,555 12,y,55512\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    let output = command(
+        ["-c", "phone", "--into", "digits", "--after", "\\D", ""],
+        b"name,phone,x\nfoo,555 12,y\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "name,phone,digits,x\nfoo,555 12,55512,y\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn rules_file_into() {
+    let rules = temp_file(
+        br#"
+[[rule]]
+column = "a"
+regex = '\d'
+replacement = "*"
+into = "masked"
+after = true
+
+[[rule]]
+column = "b"
+regex = 'x'
+replacement = "y"
+into = "b2"
+"#,
+    );
+
+    let output = command([OsStr::new("--rules"), rules.as_os_str()], b"a,b\n12,x\n");
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "a,masked,b,b2\n12,**,x,y\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn where_conditions() {
+    let input = b"item,price,currency\na,1.50,EUR\nb,2.50,USD\nc,0,EUR\n";
+
+    let output = command(
+        ["--where", "currency=~^EUR$", "-c", "price", "\\.", ","],
+        input,
+    );
+
+    assert!(output.status.success());
+    assert_eq!(
+        "item,price,currency\na,\"1,50\",EUR\nb,2.50,USD\nc,0,EUR\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    let output = command(
+        [
+            "--where",
+            "currency=~^EUR$",
+            "--where",
+            "price!~^0$",
+            "-c",
+            "price",
+            "^",
+            "=",
+        ],
+        input,
+    );
+
+    assert!(output.status.success());
+    assert_eq!(
+        "item,price,currency\na,=1.50,EUR\nb,2.50,USD\nc,0,EUR\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+
+    let output = command(
+        [
+            "--any",
+            "--where",
+            "currency=~USD",
+            "--where",
+            "item=~c",
+            "-c",
+            "price",
+            "^",
+            "=",
+        ],
+        input,
+    );
+
+    assert!(output.status.success());
+    assert_eq!(
+        "item,price,currency\na,1.50,EUR\nb,=2.50,USD\nc,=0,EUR\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn invalid_where_fails() {
+    let output = command(["--where", "currency", "-c", "0", "a", "b"], b"a\n");
+
+    assert!(!output.status.success());
+}
+
+#[test]
+fn column_references() {
+    let output = command(
+        ["-c", "display_name", "^$", "${col:last}, ${col:first}"],
+        b"first,last,display_name\nJohn,Smith,\nJane,Doe,JD\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "first,last,display_name\nJohn,Smith,\"Smith, John\"\nJane,Doe,JD\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn column_references_by_index() {
+    let output = command(
+        [
+            "--no-headers",
+            "--bytes",
+            "-c",
+            "0",
+            "(?P<n>\\d)",
+            "$n-${col:-1}",
+        ],
+        b"1,x,2\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!("1-2,x,2\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn unknown_column_reference_fails() {
+    let output = command(["-c", "a", "x", "${col:b}"], b"a\nx\n");
+
+    assert!(!output.status.success());
+}
+
+#[test]
+fn replacement_filters() {
+    let output = command(
+        [
+            "-c",
+            "name",
+            "^(\\S+) (.*)$",
+            "${2:trim:upper}, ${1:title} (${col:code:lower})",
+        ],
+        b"name,code\nmcDONALD  smith ,AB\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "name,code\n\"SMITH, Mcdonald (ab)\",AB\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn replacement_case_conversion() {
+    let output = command(
+        ["-c", "0", "^(\\w+)-(\\w+)$", "\\U$1\\E-\\L$2"],
+        b"a\nab-CD\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!("a\nAB-cd\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn default_and_present_values() {
+    let output = command(
+        [
+            "-c",
+            "a",
+            "^(\\D)(?P<n>\\d)?$",
+            "${n:-none}/${n:+has}/${1:upper:-z}",
+        ],
+        b"a\nx1\ny\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        "a\n1/has/X\nnone//Y\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn named_group_references() {
+    let output = command(["-c", "a", "(?P<name>\\d)", "${name:upper}$1$0"], b"a\n1\n");
+
+    assert!(output.status.success());
+    assert_eq!("a\n111\n", String::from_utf8_lossy(&output.stdout));
+
+    let output = command(["-c", "a", "(?P<name>\\d)", "${nmae:upper:-x}"], b"a\n1\n");
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn unknown_groups_are_reported() {
+    let output = Command::new(xsvre_exe().unwrap())
+        .args(["-c", "a", "(?P<name>\\d)", "x $nmae ${2}"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+
+    assert_eq!(
+        "\
+error: rule #1: unknown capture group \"nmae\" in replacement
+    x $nmae ${2}
+      ^^^^^
+rule #1: unknown capture group 2 in replacement
+    x $nmae ${2}
+            ^^^^
+",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn nth_and_limit() {
+    let output = command(["--nth", "2", "-c", "a", "\\.", "/"], b"a\na.b.c.d\n");
+
+    assert!(output.status.success());
+    assert_eq!("a\na.b/c.d\n", String::from_utf8_lossy(&output.stdout));
+
+    let output = command(["--limit", "2", "-c", "a", "\\.", "/"], b"a\na.b.c.d\n");
+
+    assert!(output.status.success());
+    assert_eq!("a\na/b/c.d\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn rules_file_nth() {
+    let rules = temp_file(
+        br#"
+[[rule]]
+column = "a"
+regex = '\.'
+replacement = "/"
+nth = 3
+"#,
+    );
+
+    let output = command(
+        [
+            OsStr::new("--bytes"),
+            OsStr::new("--rules"),
+            rules.as_os_str(),
+        ],
+        b"a\na.b.c.d\n",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!("a\na.b.c/d\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn regex_flags() {
+    let output = command(["--ignore-case", "-c", "a", "o+", "0"], b"a\nFOO\n");
+
+    assert!(output.status.success());
+    assert_eq!("a\nF0\n", String::from_utf8_lossy(&output.stdout));
+
+    let output = command(
+        ["--multi-line", "--swap-greed", "-c", "a", "^.+", "-"],
+        b"a\n\"ab\ncd\"\n",
+    );
+
+    assert!(output.status.success());
+    assert_eq!("a\n\"-b\n-d\"\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn no_unicode_bytes() {
+    let output = command(
+        ["--bytes", "--no-unicode", "-c", "a", "\\xff", "?"],
+        b"a\nx\xffy\n",
+    );
+
+    assert!(output.status.success());
+    assert_eq!("a\nx?y\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn full_match() {
+    let output = command(["--full", "-c", "a", "b", "X"], b"a\nabc\nb\nbb\n");
+
+    assert!(output.status.success());
+    assert_eq!("a\nabc\nX\nbb\n", String::from_utf8_lossy(&output.stdout));
+
+    let output = command(["--full", "--filter", "-c", "a", "b+"], b"a\nabc\nb\nbb\n");
+
+    assert!(output.status.success());
+    assert_eq!("a\nb\nbb\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn rules_file_full() {
+    let rules = temp_file(
+        br#"
+[[rule]]
+column = "a"
+regex = 'a|ab  # either'
+replacement = "X"
+flags = "x"
+full = true
+"#,
+    );
+
+    let output = command([OsStr::new("--rules"), rules.as_os_str()], b"a\nab\nabc\n");
+
+    assert!(output.status.success());
+
+    assert_eq!("a\nX\nabc\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn output_file_is_kept_on_error() {
+    let path = temp_file(b"a,b\n1,x y\n");
+
+    let output = Command::new(xsvre_exe().unwrap())
+        .args([
+            OsStr::new("-c"),
+            OsStr::new("c"),
+            OsStr::new("-o"),
+            path.as_os_str(),
+            OsStr::new(" "),
+            OsStr::new("_"),
+            path.as_os_str(),
+        ])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert_eq!("a,b\n1,x y\n", fs::read_to_string(&path).unwrap());
+
+    let output = command(
+        [
+            OsStr::new("-c"),
+            OsStr::new("b"),
+            OsStr::new("-o"),
+            path.as_os_str(),
+            OsStr::new(" "),
+            OsStr::new("_"),
+            path.as_os_str(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success());
+    assert_eq!("a,b\n1,x_y\n", fs::read_to_string(&path).unwrap());
+}
+
+#[test]
+fn in_place_source_column() {
+    let path = temp_file(b"a,b\n1,x y\n");
+
+    let output = command(
+        [
+            OsStr::new("-i"),
+            OsStr::new("--source-column=file"),
+            OsStr::new("-c"),
+            OsStr::new("b"),
+            OsStr::new(" "),
+            OsStr::new("_"),
+            path.as_os_str(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success());
+
+    assert_eq!(
+        format!("a,b,file\n1,x_y,{}\n", path.display()),
+        fs::read_to_string(&path).unwrap()
+    );
+}
+
+#[test]
+fn into_with_other_modes_fails() {
+    let output = command(["--filter", "--into", "b", "-c", "a", "x"], b"a\nx\n");
+
+    assert!(!output.status.success());
+
+    let output = command(["--split", "--after", "-c", "a", "x"], b"a\nx\n");
+
+    assert!(!output.status.success());
+}
+
+#[test]
+fn literal_backslash_in_replacement() {
+    let output = command(["-c", "p", "^(.*)$", "C:\\\\Users\\\\$1\\n"], b"p\nfoo\n");
+
+    assert!(output.status.success());
+    assert_eq!(
+        "p\nC:\\Users\\foo\\n\n",
+        String::from_utf8_lossy(&output.stdout)
+    );
+}
+
+#[test]
+fn rules_file_invalid_nth() {
+    for settings in ["nth = 0", "nth = 2\nlimit = 1"] {
+        let rules = temp_file(
+            format!(
+                "[[rule]]\ncolumn = \"a\"\nregex = 'b'\nreplacement = \"c\"\n{}\n",
+                settings
+            )
+            .as_bytes(),
+        );
+
+        let output = Command::new(xsvre_exe().unwrap())
+            .args([OsStr::new("--rules"), rules.as_os_str()])
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("rule on line 3:"));
+    }
+}
+
+#[test]
+fn full_match_inline_verbose() {
+    let output = command(
+        ["--full", "-c", "a", "(?x)a b # either", "X"],
+        b"a\nab\nabc\n",
+    );
+
+    assert!(output.status.success());
+    assert_eq!("a\nX\nabc\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn column_regex_without_headers_fails() {
+    let path = temp_file(b"a\nb\n");
+
+    let output = Command::new(xsvre_exe().unwrap())
+        .args([
+            OsStr::new("-n"),
+            OsStr::new("--column-regex"),
+            OsStr::new("a"),
+            OsStr::new("b"),
+            OsStr::new("c"),
+            path.as_os_str(),
+        ])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("without headers"));
+}
+
+#[cfg(unix)]
+#[test]
+fn output_file_symlink() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("target.csv");
+    let link = dir.path().join("link.csv");
+    fs::write(&target, "old\n").unwrap();
+    std::os::unix::fs::symlink(&target, &link).unwrap();
+
+    let output = command(
+        [
+            OsStr::new("-c"),
+            OsStr::new("a"),
+            OsStr::new("-o"),
+            link.as_os_str(),
+            OsStr::new(" "),
+            OsStr::new("_"),
+        ],
+        b"a\nx y\n",
+    );
+
+    assert!(output.status.success());
+
+    assert!(fs::symlink_metadata(&link)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert_eq!("a\nx_y\n", fs::read_to_string(&target).unwrap());
+}
+
+#[test]
+fn backup_without_in_place_fails() {
+    let path = temp_file(b"a\nx y\n");
+
+    let output = command(
+        [
+            OsStr::new("--backup=.bak"),
+            OsStr::new("-c"),
+            OsStr::new("a"),
+            OsStr::new(" "),
+            OsStr::new("_"),
+            path.as_os_str(),
+        ],
+        b"",
+    );
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn in_place_symlink() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = dir.path().join("target.csv");
+    let link = dir.path().join("link.csv");
+    fs::write(&target, "a\nx y\n").unwrap();
+    std::os::unix::fs::symlink(&target, &link).unwrap();
+
+    let output = command(
+        [
+            OsStr::new("-i"),
+            OsStr::new("-c"),
+            OsStr::new("a"),
+            OsStr::new(" "),
+            OsStr::new("_"),
+            link.as_os_str(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success());
+
+    assert!(fs::symlink_metadata(&link)
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert_eq!("a\nx_y\n", fs::read_to_string(&target).unwrap());
+}
+
+#[test]
+fn empty_inputs_are_skipped() {
+    let empty = temp_file(b"");
+    let path = temp_file(b"a\nfoo\n");
+
+    let output = command(
+        [
+            OsStr::new("-c"),
+            OsStr::new("a"),
+            OsStr::new("o"),
+            OsStr::new("0"),
+            empty.as_os_str(),
+            path.as_os_str(),
+            empty.as_os_str(),
+        ],
+        b"",
+    );
+
+    assert!(output.status.success());
+    assert_eq!("a\nf00\n", String::from_utf8_lossy(&output.stdout));
+}
+
+#[test]
+fn empty_delimiter_fails() {
+    let output = command(["-d", "", "-c", "a", "1", "x"], b"a;b\n1;2\n");
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn explode_with_several_rules_fails() {
+    let output = Command::new(xsvre_exe().unwrap())
+        .args(["--explode", "-e", "a", "x", "", "-e", "b", "y", ""])
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            child.stdin.take().unwrap().write_all(b"a,b\nx,y\n")?;
+            child.wait_with_output()
+        })
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert_eq!(
+        "error: exactly one rule must be given\n",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn strict_groups_is_accepted() {
+    let output = command(["--strict-groups", "-c", "a", "(\\d)", "<$1>"], b"a\n1\n");
+
+    assert!(output.status.success());
+    assert_eq!("a\n<1>\n", String::from_utf8_lossy(&output.stdout));
+
+    let output = command(["--strict-groups", "-c", "a", "(\\d)", "$2"], b"a\n1\n");
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn rules_file_invalid_flags() {
+    let rules = temp_file(
+        br#"
+[[rule]]
+column = "a"
+regex = 'abc'
+replacement = "X"
+flags = "i:q)|(?:x"
+"#,
+    );
+
+    let output = Command::new(xsvre_exe().unwrap())
+        .args([OsStr::new("--rules"), rules.as_os_str()])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unrecognized flag ':'"));
+}